@@ -0,0 +1,11 @@
+use iq_dump::mock::{MockBoard, MockServer};
+use iq_dump::sim::RfFrontEnd;
+
+fn main() -> anyhow::Result<()> {
+    simple_logger::init_with_level(log::Level::Info)?;
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:9600".into());
+    let board = MockBoard::new(Box::new(RfFrontEnd::default()));
+    let server = MockServer::with_board(&addr, board)?;
+    log::info!("mock dumpiq server listening on {}", server.local_addr()?);
+    server.run()
+}
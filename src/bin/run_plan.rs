@@ -0,0 +1,23 @@
+use iq_dump::plan::TestPlan;
+
+/// `run_plan <plan.json> [addr]`: run a test plan, against `addr` instead of
+/// the plan's board if given. Fails when any capture did not succeed.
+fn main() -> anyhow::Result<()> {
+    simple_logger::init_with_level(log::Level::Info)?;
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        anyhow::bail!("usage: run_plan <plan.json> [addr]");
+    };
+    let addr = args.next();
+    let plan = TestPlan::load(&path)?;
+    let mut dut = plan.connect(addr.as_deref())?;
+    let report = plan.run(&mut dut)?;
+    let failures: Vec<_> = report.failures().collect();
+    for point in &failures {
+        log::error!("{}", point);
+    }
+    if !failures.is_empty() {
+        anyhow::bail!("{} of {} captures did not succeed", failures.len(), report.points().len());
+    }
+    Ok(())
+}
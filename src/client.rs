@@ -1,25 +1,45 @@
+use std::collections::HashMap;
 use std::fs;
-use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use anyhow::{anyhow, Context};
-use pyo3::{pyclass, pymethods, PyResult};
+use pyo3::exceptions::{PyConnectionError, PyOSError, PyRuntimeError, PyValueError};
+use pyo3::{pyclass, pymethods, Bound, FromPyObject, Py, PyAny, PyErr, PyRefMut, PyResult, Python};
+use pyo3::types::{PyAnyMethods, PyTuple};
 use serde::{Deserialize, Serialize};
-use crate::config::{Band, GlobPhyNum, TestBand};
-use crate::config::Band::{HB, LB};
-use crate::config::GainType::{Fem, Lna, Vga};
-use crate::rfmetrics::FileParser;
-use crate::testcase::TestCase;
-
-#[derive(Serialize, Deserialize, Debug)]
-enum DumpCommand {
+use strum::{Display, EnumString, IntoStaticStr, VariantNames};
+use crate::config::{Band, BandPlan, BandState, Bandwidth, GainIndex, GainSweep, GainType, IqEngineConfig, RxConfig, TestBand};
+use crate::profile::{ChipProfile, GenericProfile, SiwifiProfile};
+use crate::regmap::RegisterMap;
+use crate::rfmetrics::{check_spread_threshold, FileParser, IqCapture, DEFAULT_SPREAD_THRESHOLD};
+use crate::snapshot::RegSnapshot;
+use crate::testcase::{ChannelSweep, PointResult, SweepReport, TestCase};
+use crate::transport;
+use crate::transport::Transport;
+
+#[derive(Serialize, Deserialize, Debug, IntoStaticStr, VariantNames)]
+pub(crate) enum DumpCommand {
     DumpIQ{
         band_5g: bool,
+        /// Band name for servers with more than two radios; old ones go by `band_5g`.
+        #[serde(default)]
+        band: String,
         file_name: String
     },
     DelFiles,
     CopyFiles(String),
+    /// `CopyFiles` starting at `offset` of the file, body compressed as requested.
+    CopyFilesEx{
+        file_name: String,
+        offset: u64,
+        compression: Compression
+    },
     SetReg{
         addr: u32,
         value: u32
@@ -30,65 +50,558 @@ enum DumpCommand {
         cmd: String,
         args: Vec<String>
     },
+    Hello{
+        client_version: String
+    },
+    GetReg{
+        addr: u32
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct ResponseHeader {
+    pub(crate) is_error: bool,
+    pub(crate) file_size: u64,
+    /// CRC32 of the body that follows, sent by servers that support verification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) crc32: Option<u32>,
+    /// Encoding of a `CopyFilesEx` body; servers may ignore the requested one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compression: Option<Compression>,
+    /// File offset a `CopyFilesEx` body starts at.
+    #[serde(default)]
+    pub(crate) offset: u64,
+    /// Register content answering `GetReg`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Result of a `ShellCmd` or `ATECmd`, the JSON body of its response. Servers
+/// that predate it send no body, leaving everything but `is_error` empty.
+#[pyclass(get_all)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CmdOutput {
+    #[serde(skip)]
+    pub is_error: bool,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+}
+
+impl CmdOutput {
+    pub fn success(&self) -> bool {
+        !self.is_error && self.exit_code.unwrap_or(0) == 0
+    }
+}
+
+#[pymethods]
+impl CmdOutput {
+    #[getter(success)]
+    fn py_success(&self) -> bool {
+        self.success()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("CmdOutput(exit_code={:?}, is_error={}, stdout={:?}, stderr={:?})",
+            self.exit_code, self.is_error, self.stdout, self.stderr)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct  ResponseHeader {
-    is_error: bool,
-    file_size: u64,
+/// How often `copy_files` fetches a file whose checksum does not match.
+const COPY_ATTEMPTS: u32 = 3;
+
+/// Where a fetched file body goes; a partial one is what a resume continues from.
+enum FetchTarget {
+    File(PathBuf),
+    Memory(Vec<u8>),
+}
+
+impl FetchTarget {
+    fn len(&self) -> u64 {
+        match self {
+            FetchTarget::File(path) => fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+            FetchTarget::Memory(body) => body.len() as u64,
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            FetchTarget::File(path) => { let _ = fs::remove_file(path); }
+            FetchTarget::Memory(body) => body.clear(),
+        }
+    }
+
+    fn writer(&mut self, append: bool) -> io::Result<Box<dyn Write + '_>> {
+        match self {
+            FetchTarget::File(path) => {
+                let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(path)?;
+                Ok(Box::new(BufWriter::new(file)))
+            }
+            FetchTarget::Memory(body) => {
+                if !append {
+                    body.clear();
+                }
+                Ok(Box::new(body))
+            }
+        }
+    }
+}
+
+/// What a dumpiq_server build can do, the JSON body of its `Hello` response.
+#[pyclass(get_all)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    pub server_version: String,
+    pub chip_model: String,
+    /// Supported `DumpCommand` variant names, plus `DumpIQBand` when `DumpIQ`
+    /// captures the radio named by its `band` field.
+    pub commands: Vec<String>,
+    pub max_transfer_size: u64,
+}
+
+/// Largest body accepted from a server that does not announce a limit, well
+/// above the biggest IQ dump, so a corrupt header can not ask for gigabytes.
+pub const LEGACY_MAX_TRANSFER_SIZE: u64 = 64 * 1024 * 1024;
+
+impl Capabilities {
+    /// Servers that predate `Hello` implement exactly the original command set.
+    pub fn legacy() -> Self {
+        Self {
+            server_version: "unknown".into(),
+            chip_model: "unknown".into(),
+            commands: ["DumpIQ", "DelFiles", "CopyFiles", "SetReg", "ShellCmd", "ATEInit", "ATECmd"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            max_transfer_size: LEGACY_MAX_TRANSFER_SIZE,
+        }
+    }
+
+    pub fn supports(&self, command: &str) -> bool {
+        self.commands.iter().any(|c| c == command)
+    }
+}
+
+/// Link supervision settings for a `Dut`.
+#[derive(Debug, Clone)]
+pub struct DutOptions {
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// Probe the link before a step when it has been idle this long.
+    pub heartbeat_interval: Option<Duration>,
+    /// Reconnect attempts per step before giving up.
+    pub max_retries: u32,
+    pub retry_delay: Duration,
+    /// Body encoding asked for on file transfers.
+    pub compression: Compression,
+    /// Read every register written by `fix_gain`/`shut_down_band` back and
+    /// fail if it did not stick. Needs a server supporting `GetReg`.
+    pub verify_writes: bool,
+    /// Snapshot the registers before a `run_test` sweep and restore them after.
+    pub restore_registers: bool,
+    /// Bring a band up and open its RX when a command needs it, instead of
+    /// failing.
+    pub auto_transitions: bool,
+}
+
+impl Default for DutOptions {
+    fn default() -> Self {
+        Self {
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(10)),
+            heartbeat_interval: Some(Duration::from_secs(60)),
+            max_retries: 3,
+            retry_delay: Duration::from_secs(2),
+            compression: Compression::None,
+            verify_writes: false,
+            restore_registers: false,
+            auto_transitions: false,
+        }
+    }
+}
+
+/// Board state a reconnect has to re-establish.
+#[derive(Debug, Default)]
+struct Session {
+    ate_initialized: bool,
+    fixed_gain: Option<(Band, u8, u8, u8)>,
 }
 
 pub struct Dut {
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
+    reader: BufReader<Box<dyn Transport>>,
+    addr: Option<String>,
+    options: DutOptions,
+    session: Session,
+    capabilities: Capabilities,
+    last_activity: Instant,
+    profile: Box<dyn ChipProfile>,
+    /// Phy index of each band, as found on the board by `discover_phys`.
+    phys: HashMap<Band, u8>,
+    bands: HashMap<Band, BandState>,
+    /// Capture settings replacing the profile's defaults, per band.
+    iq_engine: HashMap<Band, IqEngineConfig>,
+    interrupt: Option<Box<dyn Fn() -> anyhow::Result<()> + Send + Sync>>,
+    regmap: RegisterMap,
+    /// Last value written to each register, the base for `write_field` when
+    /// the server has no GetReg.
+    reg_shadow: HashMap<u32, u32>,
+    /// Registers covered by `restore_registers`; empty means every register in the map.
+    snapshot_ranges: Vec<RangeInclusive<u32>>,
+    dump_dir: PathBuf,
     pub(crate) file_list: FileParser
 }
 
 impl Dut {
 
-    pub fn new(addr: &str) -> Dut {
-        let stream = TcpStream::connect(addr).expect("Could not connect to server");
-        let reader = BufReader::new(stream.try_clone().expect("Could not clone stream"));
+    /// Connect to a dumpiq server, see `transport::open` for the address forms.
+    pub fn connect(addr: &str) -> anyhow::Result<Dut> {
+        let mut dut = Self::with_transport(transport::open(addr)?);
+        dut.addr = Some(addr.to_string());
+        dut.set_options(DutOptions::default())?;
+        dut.open_session()?;
+        Ok(dut)
+    }
+
+    /// Drive a board over an already open transport. Such a `Dut` has no address
+    /// to reconnect to, so link errors are returned as they are. It assumes the
+    /// legacy command set until `handshake` is called.
+    pub fn with_transport(transport: Box<dyn Transport>) -> Dut {
         Dut {
-            stream,
-            reader,
+            reader: BufReader::new(transport),
+            addr: None,
+            options: DutOptions::default(),
+            session: Session::default(),
+            capabilities: Capabilities::legacy(),
+            last_activity: Instant::now(),
+            profile: Box::new(SiwifiProfile),
+            phys: HashMap::new(),
+            bands: HashMap::new(),
+            iq_engine: HashMap::new(),
+            interrupt: None,
+            regmap: RegisterMap::siwifi(),
+            reg_shadow: HashMap::new(),
+            snapshot_ranges: Vec::new(),
+            dump_dir: PathBuf::from("./iq_dump"),
             file_list: FileParser::new(Vec::new())
         }
     }
 
+    pub fn set_options(&mut self, options: DutOptions) -> anyhow::Result<()> {
+        self.reader.get_mut().set_timeouts(options.read_timeout, options.write_timeout)?;
+        self.options = options;
+        Ok(())
+    }
+
+    pub fn options(&self) -> &DutOptions {
+        &self.options
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Ask the server for its capabilities. Servers that answer `Hello` with an
+    /// error or an unreadable body are treated as legacy ones.
+    pub fn handshake(&mut self) -> anyhow::Result<&Capabilities> {
+        self.send_cmd(DumpCommand::Hello { client_version: env!("CARGO_PKG_VERSION").into() })?;
+        let res = self.handle_resp()?;
+        let body = self.read_body(res.file_size)?;
+        self.capabilities = if res.is_error {
+            log::warn!("Server does not understand Hello, assuming legacy command set");
+            Capabilities::legacy()
+        } else {
+            serde_json::from_slice(&body).unwrap_or_else(|e| {
+                log::warn!("Could not parse server capabilities ({}), assuming legacy command set", e);
+                Capabilities::legacy()
+            })
+        };
+        log::info!("Server {} on {}, commands {:?}", self.capabilities.server_version,
+            self.capabilities.chip_model, self.capabilities.commands);
+        Ok(&self.capabilities)
+    }
+
+    /// Handshake on a freshly opened link. Old servers may hang up on the
+    /// unknown `Hello`; reopen the link once and fall back to the legacy set.
+    fn open_session(&mut self) -> anyhow::Result<()> {
+        match self.handshake() {
+            Ok(_) => Ok(()),
+            Err(e) if transport::is_link_error(&e) => {
+                log::warn!("Handshake failed: {:#}, assuming legacy command set", e);
+                self.reopen()?;
+                self.capabilities = Capabilities::legacy();
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Target another SoC. Also switches to the profile's register map.
+    pub fn set_profile(&mut self, profile: Box<dyn ChipProfile>) {
+        log::info!("Using chip profile {}", profile.name());
+        self.regmap = profile.register_map();
+        self.profile = profile;
+        self.phys.clear();
+    }
+
+    /// Bands of the current profile.
+    pub fn bands(&self) -> Vec<Band> {
+        self.profile.bands()
+    }
+
+    /// The profile's band called `name`.
+    pub fn band(&self, name: &str) -> anyhow::Result<Band> {
+        let band = name.parse()?;
+        self.check_band(band)?;
+        Ok(band)
+    }
+
+    fn check_band(&self, band: Band) -> anyhow::Result<()> {
+        if !self.profile.bands().contains(&band) {
+            return Err(anyhow!("Profile {} has no band {}, only {:?}", self.profile.name(), band,
+                self.profile.bands().iter().map(|b| b.name()).collect::<Vec<_>>()));
+        }
+        Ok(())
+    }
+
+    pub fn band_plan(&self, band: Band) -> anyhow::Result<BandPlan> {
+        self.check_band(band)?;
+        self.profile.band_plan(band)
+            .ok_or_else(|| anyhow!("Profile {} has no channel plan for {}", self.profile.name(), band))
+    }
+
+    /// Replace the built-in siwifi register map, e.g. for another chip revision.
+    pub fn load_regmap(&mut self, path: &str) -> anyhow::Result<()> {
+        self.regmap = RegisterMap::load(path)?;
+        Ok(())
+    }
+
+    /// Directory copied IQ files are written to, `./iq_dump` by default.
+    pub fn set_dump_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.dump_dir = dir.into();
+    }
+
+    pub fn dump_dir(&self) -> &Path {
+        &self.dump_dir
+    }
+
+    /// Where `copy_files` puts `file_name`.
+    pub fn dump_path(&self, file_name: &str) -> PathBuf {
+        self.dump_dir.join(file_name)
+    }
+
     fn handle_resp(&mut self) -> anyhow::Result<ResponseHeader> {
         let mut header_line = String::new();
-        self.reader.read_line(&mut header_line)?;
+        if self.reader.read_line(&mut header_line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server").into());
+        }
+        self.last_activity = Instant::now();
         let resp: ResponseHeader = serde_json::from_str(&header_line)
             .with_context(|| format!("Could not parse response header line {}", header_line))?;
         Ok(resp)
     }
 
+    fn read_body(&mut self, size: u64) -> anyhow::Result<Vec<u8>> {
+        if size > self.capabilities.max_transfer_size {
+            return Err(anyhow!("Response body of {} bytes exceeds server max transfer size {}",
+                size, self.capabilities.max_transfer_size));
+        }
+        // grow with the data actually received instead of trusting the header
+        let mut body = Vec::new();
+        (&mut self.reader).take(size).read_to_end(&mut body)?;
+        if (body.len() as u64) < size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("response body ended after {} of {} bytes", body.len(), size)).into());
+        }
+        Ok(body)
+    }
+
     fn send_cmd(&mut self, cmd: DumpCommand) -> anyhow::Result<()> {
-        let json_req = serde_json::to_string(&cmd)?;
-        self.stream.write_all(json_req.as_bytes())?;
-        self.stream.write_all(b"\n")?;
+        let name: &'static str = (&cmd).into();
+        if !matches!(cmd, DumpCommand::Hello { .. }) && !self.capabilities.supports(name) {
+            return Err(anyhow!("Server {} does not support {}", self.capabilities.server_version, name));
+        }
+        let mut json_req = serde_json::to_string(&cmd)?;
+        json_req.push('\n');
+        self.reader.get_mut().write_all(json_req.as_bytes())?;
         Ok(())
     }
 
-    pub fn dump_iq(&mut self, band_5g: Band, file_name: String) -> anyhow::Result<bool> {
-        // Send command
-        let cmd = if band_5g == Band::HB {
-            format!("echo 0 1 0 15 0 e000 0 2 0  1 0 0 0 > /sys/kernel/debug/ieee80211/phy{}/siwifi/iq_engine", GlobPhyNum::hb())
+    /// Round trip a no-op shell command to check the board still answers.
+    pub fn heartbeat(&mut self) -> anyhow::Result<()> {
+        self.shell_cmd("true")?;
+        Ok(())
+    }
+
+    /// Open a fresh connection and restore the ATE session and the last fixed gain.
+    pub fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.reopen()?;
+        self.open_session()?;
+        // the board may have rebooted and numbered its phys differently
+        self.phys.clear();
+        self.reg_shadow.clear();
+
+        if self.session.ate_initialized {
+            self.ate_init()?;
+        }
+        if let Some((band, fem, lna, vga)) = self.session.fixed_gain {
+            self.fix_gain(band, fem, lna, vga)?;
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> anyhow::Result<()> {
+        let addr = self.addr.clone()
+            .ok_or_else(|| anyhow!("Dut was not created from an address, can not reconnect"))?;
+        log::warn!("Reconnecting to {}", addr);
+        self.reader = BufReader::new(transport::open(&addr)?);
+        self.set_options(self.options.clone())?;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    /// Run one test step, reconnecting and retrying it when the link drops or hangs.
+    pub fn resilient<T>(&mut self, mut step: impl FnMut(&mut Dut) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut attempt = 0;
+        loop {
+            let res = self.keepalive().and_then(|_| step(self));
+            match res {
+                Err(e) if self.addr.is_some() && transport::is_link_error(&e) && attempt < self.options.max_retries => {
+                    attempt += 1;
+                    log::warn!("Link error: {:#}, retry {}/{}", e, attempt, self.options.max_retries);
+                    thread::sleep(self.options.retry_delay);
+                    if let Err(e) = self.reconnect() {
+                        log::warn!("Reconnect failed: {:#}", e);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn keepalive(&mut self) -> anyhow::Result<()> {
+        match self.options.heartbeat_interval {
+            Some(interval) if self.last_activity.elapsed() >= interval => self.heartbeat(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run a shell command on the board. A failing command is not an error
+    /// here; check `CmdOutput::success`.
+    pub fn shell_cmd(&mut self, cmd: &str) -> anyhow::Result<CmdOutput> {
+        let out = self.cmd_output(DumpCommand::ShellCmd(cmd.into()))?;
+        if !out.success() {
+            log::warn!("`{}` failed: exit {:?} {}", cmd, out.exit_code, out.stderr.trim());
+        }
+        Ok(out)
+    }
+
+    pub fn ate_cmd(&mut self, cmd: &str, args: Vec<String>) -> anyhow::Result<CmdOutput> {
+        let line = format!("{} {}", cmd, args.join(" "));
+        let out = self.cmd_output(DumpCommand::ATECmd { cmd: cmd.into(), args })?;
+        if !out.success() {
+            log::warn!("`{}` failed: exit {:?} {}", line, out.exit_code, out.stderr.trim());
+        }
+        Ok(out)
+    }
+
+    fn cmd_output(&mut self, cmd: DumpCommand) -> anyhow::Result<CmdOutput> {
+        self.send_cmd(cmd)?;
+        let res = self.handle_resp()?;
+        let body = self.read_body(res.file_size)?;
+        let mut out = if body.is_empty() {
+            CmdOutput::default()
         } else {
-            format!("echo 0 1 0 15 0 1c000 0 2 0  1 0 0 0 > /sys/kernel/debug/ieee80211/phy{}/siwifi/iq_engine", GlobPhyNum::lb())
+            serde_json::from_slice(&body).context("Could not parse command output")?
         };
-        let cmd = DumpCommand::ShellCmd(cmd.into());
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
+        out.is_error = res.is_error;
+        Ok(out)
+    }
+
+    pub fn dump_iq(&mut self, band_5g: Band, file_name: String) -> anyhow::Result<bool> {
+        self.require_band(band_5g, BandState::RxOpen, "dump_iq")?;
+        // older servers ignore `band` and would capture LB instead
+        if !matches!(band_5g, Band::HB | Band::LB) && !self.capabilities.supports("DumpIQBand") {
+            return Err(anyhow!("Server {} can only dump HB and LB, not {}", self.capabilities.server_version, band_5g));
+        }
+        // Send command
+        let phy = self.phy(band_5g)?;
+        let config = self.iq_engine(band_5g);
+        config.validate()?;
+        let cmd = self.profile.iq_engine_cmd(band_5g, phy, &config);
+        self.shell_cmd(&cmd)?;
 
-        let cmd = DumpCommand::DumpIQ{band_5g: band_5g == Band::HB, file_name};
+        // a partial copy of an earlier capture under this name must not be resumed
+        let _ = fs::remove_file(self.part_path(&file_name));
+        let cmd = DumpCommand::DumpIQ{band_5g: band_5g == Band::HB, band: band_5g.to_string(), file_name};
         self.send_cmd(cmd)?;
 
         // read response
         Ok(!self.handle_resp()?.is_error)
     }
 
+    /// Capture settings `dump_iq` uses for `band`.
+    pub fn iq_engine(&self, band: Band) -> IqEngineConfig {
+        self.iq_engine.get(&band).cloned()
+            .unwrap_or_else(|| self.profile.iq_engine_default(band))
+    }
+
+    /// Use `config` for every later `dump_iq` on `band`; `None` goes back to
+    /// the profile's default.
+    pub fn set_iq_engine(&mut self, band: Band, config: Option<IqEngineConfig>) -> anyhow::Result<()> {
+        match config {
+            Some(config) => {
+                config.validate()?;
+                self.iq_engine.insert(band, config);
+            }
+            None => {
+                self.iq_engine.remove(&band);
+            }
+        }
+        Ok(())
+    }
+
+    /// Phy index of `band`, looked up on the board the first time it is needed.
+    pub fn phy(&mut self, band: Band) -> anyhow::Result<u8> {
+        if !self.phys.contains_key(&band) {
+            self.discover_phys()?;
+        }
+        self.phys.get(&band).copied()
+            .ok_or_else(|| anyhow!("No phy is bound to {} ({}), is the band shut down?",
+                self.profile.phy_device(band), band))
+    }
+
+    /// Match every wiphy under `/sys/class/ieee80211` to the device it belongs to.
+    pub fn discover_phys(&mut self) -> anyhow::Result<&HashMap<Band, u8>> {
+        let out = self.shell_cmd("ls -l /sys/class/ieee80211/")?;
+        if !out.success() {
+            return Err(anyhow!("Could not list wiphys: {}", out.stderr.trim()));
+        }
+        let links = parse_phy_links(&out.stdout);
+        self.phys.clear();
+        for band in self.profile.bands() {
+            let device = self.profile.phy_device(band);
+            let found = links.iter()
+                .find(|(_, target)| target.split('/').any(|part| part == device));
+            if let Some((idx, _)) = found {
+                self.phys.insert(band, *idx);
+            }
+        }
+        log::info!("Discovered phys {:?}", self.phys);
+        Ok(&self.phys)
+    }
+
     pub fn del_files(&mut self) -> anyhow::Result<bool> {
         //send command
         let cmd = DumpCommand::DelFiles;
@@ -98,38 +611,245 @@ impl Dut {
         Ok(!self.handle_resp()?.is_error)
     }
 
+    /// Fetch `file_name` from the board into the dump dir. Servers supporting
+    /// `CopyFilesEx` send it compressed per `DutOptions::compression` and let an
+    /// interrupted download resume from the bytes already received.
     pub fn copy_files(&mut self, file_name: String) -> anyhow::Result<bool> {
-        let cmd = DumpCommand::CopyFiles(file_name.clone());
-        self.send_cmd(cmd)?;
-        if !Path::new("./iq_dump").exists() {
-            fs::create_dir_all("./iq_dump")?;
+        if !self.dump_dir.exists() {
+            fs::create_dir_all(&self.dump_dir)?;
         }
-        //read response
-        let res = self.handle_resp()?;
-        if res.is_error {
-            log::error!("Could not copy files! {}", file_name);
-            Err(anyhow!("Could not copy files!"))
-        } else {
+        let file_path = self.dump_path(&file_name);
+        let mut target = FetchTarget::File(self.part_path(&file_name));
+        // a `.part` left by an interrupted copy is resumed, if the server can
+        if !self.capabilities.supports("CopyFilesEx") {
+            target.reset();
+        }
+        self.fetch(&file_name, &mut target)?;
+        if let FetchTarget::File(part_path) = target {
+            fs::rename(&part_path, &file_path)?;
+        }
+        self.file_list.add_file(file_path.display().to_string());
+        Ok(true)
+    }
+
+    /// Partial download of `file_name`, renamed once complete.
+    fn part_path(&self, file_name: &str) -> PathBuf {
+        self.dump_dir.join(format!("{}.part", file_name))
+    }
+
+    /// Dump one capture and return its samples without going through a file.
+    /// With `archive` the text is also saved to the dump dir like `copy_files` does.
+    pub fn capture(&mut self, band: Band, file_name: &str, archive: bool) -> anyhow::Result<IqCapture> {
+        if !self.dump_iq(band, file_name.to_string())? {
+            return Err(anyhow!("Could not dump iq {}", file_name));
+        }
+        let mut target = FetchTarget::Memory(Vec::new());
+        let fetched = self.fetch(file_name, &mut target);
+        self.del_files()?;
+        fetched?;
+
+        let FetchTarget::Memory(body) = target else { unreachable!() };
+        if archive {
+            fs::create_dir_all(&self.dump_dir)?;
+            let file_path = self.dump_dir.join(file_name);
+            fs::write(&file_path, &body)?;
+            self.file_list.add_file(file_path.display().to_string());
+        }
+        IqCapture::parse(body.as_slice()).with_context(|| format!("parse {}", file_name))
+    }
+
+    fn fetch(&mut self, file_name: &str, target: &mut FetchTarget) -> anyhow::Result<()> {
+        let extended = self.capabilities.supports("CopyFilesEx");
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let offset = if extended { target.len() } else { 0 };
+            let cmd = if extended {
+                DumpCommand::CopyFilesEx { file_name: file_name.to_string(), offset, compression: self.options.compression }
+            } else {
+                DumpCommand::CopyFiles(file_name.to_string())
+            };
+            self.send_cmd(cmd)?;
+            //read response
+            let res = self.handle_resp()?;
+            if res.is_error {
+                log::error!("Could not copy files! {}", file_name);
+                target.reset();
+                return Err(anyhow!("Could not copy files!"));
+            }
+            if res.file_size > self.capabilities.max_transfer_size {
+                target.reset();
+                return Err(anyhow!("File {} of {} bytes exceeds server max transfer size {}",
+                    file_name, res.file_size, self.capabilities.max_transfer_size));
+            }
+            if res.offset != 0 && res.offset != offset {
+                target.reset();
+                return Err(anyhow!("Server resumed {} at {} instead of {}", file_name, res.offset, offset));
+            }
+
             log::info!("Copy file ing...");
-            let mut buffer = vec![0u8; 64*1024];
-            let mut remaining = res.file_size;
-            let mut file = BufWriter::new(File::create(format!("./iq_dump/{}", file_name))?);
-
-            while remaining > 0 {
-                let read_len = std::cmp::min(remaining, buffer.len() as u64) as usize;
-                let n = self.reader.read(&mut buffer[..read_len])?;
-                if n == 0 {
-                    return Err(anyhow!("Not completely receive file!"));
+            let start = Instant::now();
+            let compression = res.compression.unwrap_or_default();
+            let received = target.writer(res.offset > 0)
+                .map_err(anyhow::Error::from)
+                .and_then(|out| self.receive(out, res.file_size, compression));
+            let crc = match received {
+                Ok(crc) => crc,
+                Err(e) if extended && self.addr.is_some() && transport::is_link_error(&e) && attempt < COPY_ATTEMPTS => {
+                    log::warn!("Transfer of {} interrupted: {:#}, resuming (attempt {}/{})",
+                        file_name, e, attempt, COPY_ATTEMPTS);
+                    self.reconnect()?;
+                    continue;
                 }
-                file.write_all(&buffer[..n])?;
-                remaining -= n as u64;
+                // keep what arrived so the next `copy_files` picks up from there
+                Err(e) if extended && transport::is_link_error(&e) => return Err(e),
+                Err(e) => {
+                    target.reset();
+                    return Err(e);
+                }
+            };
+            let secs = start.elapsed().as_secs_f64().max(1e-6);
+
+            match res.crc32 {
+                Some(expected) if expected != crc => {
+                    target.reset();
+                    log::warn!("Checksum mismatch on {}: expected {:08x}, got {:08x} (attempt {}/{})",
+                        file_name, expected, crc, attempt, COPY_ATTEMPTS);
+                    if attempt >= COPY_ATTEMPTS {
+                        return Err(anyhow!("Checksum mismatch on {} after {} attempts", file_name, COPY_ATTEMPTS));
+                    }
+                    continue;
+                }
+                Some(_) => {}
+                None => log::debug!("Server sent no checksum for {}, not verified", file_name),
+            }
+            log::info!("Saved file {} ({} bytes {:?} from offset {} in {:.2}s, {:.2} MB/s)",
+                file_name, res.file_size, compression, res.offset, secs, res.file_size as f64 / secs / 1e6);
+            return Ok(());
+        }
+    }
+
+    /// Stream a response body of `size` bytes into `out`, decompressing it on
+    /// the way, and return the CRC32 of the bytes as received.
+    fn receive(&mut self, out: Box<dyn Write + '_>, size: u64, compression: Compression) -> anyhow::Result<u32> {
+        let mut buffer = vec![0u8; 64*1024];
+        let mut remaining = size;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut sink: Box<dyn Write> = match compression {
+            Compression::None => out,
+            Compression::Gzip => Box::new(flate2::write::GzDecoder::new(out)),
+            Compression::Zstd => Box::new(zstd::stream::write::Decoder::new(out)?),
+        };
+
+        while remaining > 0 {
+            let read_len = std::cmp::min(remaining, buffer.len() as u64) as usize;
+            let n = self.reader.read(&mut buffer[..read_len])?;
+            if n == 0 {
+                sink.flush()?;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Not completely receive file!").into());
+            }
+            hasher.update(&buffer[..n]);
+            sink.write_all(&buffer[..n])?;
+            remaining -= n as u64;
+        }
+
+        sink.flush()?;
+        Ok(hasher.finalize())
+    }
+
+    pub fn read_reg(&mut self, addr: u32) -> anyhow::Result<u32> {
+        self.send_cmd(DumpCommand::GetReg { addr })?;
+        let res = self.handle_resp()?;
+        match res.value {
+            Some(value) if !res.is_error => Ok(value),
+            _ => Err(anyhow!("Could not read register 0x{:08x}", addr)),
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u32, value: u32) -> anyhow::Result<()> {
+        self.send_cmd(DumpCommand::SetReg { addr, value })?;
+        if self.handle_resp()?.is_error {
+            return Err(anyhow!("Server reported an error writing 0x{:08x} to register 0x{:08x}", value, addr));
+        }
+        self.reg_shadow.insert(addr, value);
+        if self.options.verify_writes {
+            let read = self.read_reg(addr)?;
+            if read != value {
+                return Err(anyhow!("Register 0x{:08x} reads back 0x{:08x} after writing 0x{:08x}", addr, read, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `value` into one bitfield of a register from the register map,
+    /// keeping the other bits as read back, or as last written when the
+    /// server can not read registers.
+    pub fn write_field(&mut self, reg: &str, field: &str, value: u32) -> anyhow::Result<()> {
+        let reg = self.regmap.register(reg)?;
+        let current = match self.reg_shadow.get(&reg.addr) {
+            Some(current) if !self.capabilities.supports("GetReg") => *current,
+            _ => self.read_reg(reg.addr)
+                .with_context(|| format!("Can not read-modify-write {}", reg.name))?,
+        };
+        let word = reg.field(field)?.insert(current, value)
+            .with_context(|| format!("{}.{}", reg.name, field))?;
+        self.write_reg(reg.addr, word)
+    }
+
+    pub fn read_field(&mut self, reg: &str, field: &str) -> anyhow::Result<u32> {
+        let reg = self.regmap.register(reg)?;
+        let field = reg.field(field)?;
+        Ok(field.extract(self.read_reg(reg.addr)?))
+    }
+
+    pub fn set_snapshot_ranges(&mut self, ranges: Vec<RangeInclusive<u32>>) {
+        self.snapshot_ranges = ranges;
+    }
+
+    /// Ranges `snapshot_regs` reads when none are given.
+    pub fn snapshot_ranges(&self) -> Vec<RangeInclusive<u32>> {
+        if self.snapshot_ranges.is_empty() {
+            self.regmap.addresses().into_iter().map(|addr| addr..=addr).collect()
+        } else {
+            self.snapshot_ranges.clone()
+        }
+    }
+
+    pub fn snapshot_regs(&mut self, ranges: &[RangeInclusive<u32>]) -> anyhow::Result<RegSnapshot> {
+        let mut snapshot = RegSnapshot::default();
+        for addr in RegSnapshot::addresses(ranges) {
+            snapshot.regs.insert(addr, self.read_reg(addr)?);
+        }
+        log::info!("Snapshot of {} registers taken", snapshot.regs.len());
+        Ok(snapshot)
+    }
+
+    /// Write back every register of `snapshot` whose value has changed since.
+    /// Returns the number of registers written.
+    pub fn restore_regs(&mut self, snapshot: &RegSnapshot) -> anyhow::Result<usize> {
+        let mut current = RegSnapshot::default();
+        for &addr in snapshot.regs.keys() {
+            current.regs.insert(addr, self.read_reg(addr)?);
+        }
+        let changes = snapshot.diff(&current);
+        for change in &changes {
+            if let Some(value) = change.before {
+                log::info!("Restore 0x{:08x}: 0x{:08x} -> 0x{:08x}", change.addr, change.after.unwrap_or(0), value);
+                self.write_reg(change.addr, value)?;
             }
+        }
+        // a reconnect must not put the gain the snapshot just undid back
+        self.session.fixed_gain = None;
+        Ok(changes.len())
+    }
 
-            file.flush()?;
-            log::info!("Saved file {}", file_name);
-            self.file_list.add_file(format!("./iq_dump/{}", file_name));
-            Ok(true)
+    /// Run a named write sequence from the register map.
+    pub fn run_sequence(&mut self, name: &str, params: &HashMap<String, String>) -> anyhow::Result<()> {
+        for (addr, value) in self.regmap.sequence(name, params)? {
+            self.write_reg(addr, value)?;
         }
+        Ok(())
     }
 
     pub fn fix_gain(&mut self, is_hb:Band, fem: u8, lna: u8, vga: u8) -> anyhow::Result<()> {
@@ -142,31 +862,20 @@ impl Dut {
         // devmem 0x20c02f88 32 0x3d171d17
         // devmem 0x20c02f88 32 0x24000400
         // devmem 0x20c02f88 32 0x34001400
-        let addr = if is_hb == Band::HB {
-            0x30c02f88
-        } else {
-            0x20c02f88
-        };
-        let gain_value = pack_bit(fem, lna, vga);
-
-        let cmd = DumpCommand::SetReg {addr, value: 0x2d170d17};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
-
-        let cmd = DumpCommand::SetReg {addr, value: 0x3d171d17};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
-
-        let value = (gain_value as u32 | 0x2000) << 16 | (gain_value as u32 | 0x0000);
-        let cmd = DumpCommand::SetReg {addr, value};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
-
-        let value = (gain_value as u32 | 0x3000) << 16 | (gain_value as u32 | 0x1000);
-        let cmd = DumpCommand::SetReg {addr, value};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
+        let gain = GainIndex::new(fem, lna, vga);
+        gain.validate()?;
+        log::debug!("Fix {} gain to word 0x{:04x}", is_hb, gain.to_word());
+        let params = HashMap::from([
+            ("band".to_string(), is_hb.to_string()),
+            ("fem".to_string(), fem.to_string()),
+            ("lna".to_string(), lna.to_string()),
+            ("vga".to_string(), vga.to_string()),
+        ]);
+        self.require_band(is_hb, BandState::RxOpen, "fix_gain")?;
+        self.run_sequence("fix_gain", &params)?;
 
+        self.session.fixed_gain = Some((is_hb, fem, lna, vga));
+        self.set_band_state(is_hb, BandState::GainFixed);
         Ok(())
     }
 
@@ -175,222 +884,768 @@ impl Dut {
         // devmem 0x04e00030 32 0xffff
         // devmem 0x04e00478 32 7
         // devmem 0x04e004c8 32 7
-        let cmd = if band_5g == Band::HB {
-            "echo 30000000.wmac > /sys/bus/platform/drivers/siwifi_umac/unbind"
-        } else {
-            "echo 20000000.wmac > /sys/bus/platform/drivers/siwifi_umac/unbind"
-        };
-        let cmd = DumpCommand::ShellCmd(cmd.into());
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
-
-        let cmd = DumpCommand::SetReg { addr: 0x04e00030, value: 0xffff};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
-
-        let cmd = DumpCommand::SetReg { addr: 0x04e00478, value: 7};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
+        self.check_band(band_5g)?;
+        let cmd = self.profile.unbind_cmd(band_5g);
+        self.shell_cmd(&cmd)?;
+        self.phys.remove(&band_5g);
+        self.set_band_state(band_5g, BandState::Unbound);
 
-        let cmd = DumpCommand::SetReg { addr: 0x04e004c8, value: 7};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
+        let params = HashMap::from([("band".to_string(), band_5g.to_string())]);
+        self.run_sequence("shut_down_band", &params)?;
 
         log::info!("Shut {} Donw Over!", band_5g);
         Ok(())
     }
 
     pub fn shut_up_band(&mut self, band_5g: Band) -> anyhow::Result<()> {
-        let cmd = if band_5g == Band::HB {
-            "echo 30000000.wmac > /sys/bus/platform/drivers/siwifi_umac/bind"
-        } else {
-            "echo 20000000.wmac > /sys/bus/platform/drivers/siwifi_umac/bind"
-        };
-        let cmd = DumpCommand::ShellCmd(cmd.into());
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
-
-        let args = if band_5g == Band::HB {
-            ["wlan0", "up"]
-        } else {
-            ["wlan1", "up"]
-        }
-            .iter()
-            .map(|s| {s.to_string()})
-            .collect::<Vec<_>>();
-        let cmd = DumpCommand::ATECmd {cmd: "ifconfig".into(), args};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
+        self.check_band(band_5g)?;
+        let cmd = self.profile.bind_cmd(band_5g);
+        self.shell_cmd(&cmd)?;
+        // rebinding registers a new wiphy with a fresh index
+        self.phys.remove(&band_5g);
+        self.set_band_state(band_5g, BandState::Bound);
+        self.iface_up(band_5g)
+    }
 
-        if band_5g == Band::HB {
-            GlobPhyNum::add_hb()
-        } else {
-            GlobPhyNum::add_lb()
+    fn iface_up(&mut self, band: Band) -> anyhow::Result<()> {
+        let (cmd, args) = self.profile.iface_up(band);
+        let out = self.ate_cmd(&cmd, args)?;
+        if !out.success() {
+            return Err(anyhow!("Could not bring {} up: {}", band, out.stderr.trim()));
         }
+        self.set_band_state(band, BandState::Up);
         Ok(())
     }
 
     pub fn ate_init(&mut self) -> anyhow::Result<()> {
         self.send_cmd(DumpCommand::ATEInit)?;
         let res = self.handle_resp()?;
+        self.session.ate_initialized = true;
 
         log::info!("Ate init status error?{}", res.is_error);
         Ok(())
     }
 
     pub fn open_rx(&mut self, is_hb: Band) -> anyhow::Result<()> {
-        let args = if is_hb == Band::HB {
-            "wlan0 fastconfig -f 5180 -c 5180 -w 1 -u 1 -r"
-        } else {
-            "wlan1 fastconfig -f 2412 -c 2412 -w 1 -u 1 -r"
-        }
-            .trim()
-            .split(" ")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        let cmd = DumpCommand::ATECmd{cmd: "ate_cmd".into(), args};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
+        let config = RxConfig::default_for(&self.band_plan(is_hb)?);
+        self.open_rx_with(is_hb, &config)
+    }
+
+    /// `open_rx` on the channel in `config` instead of the band's default one.
+    pub fn open_rx_with(&mut self, is_hb: Band, config: &RxConfig) -> anyhow::Result<()> {
+        config.validate(is_hb, &self.band_plan(is_hb)?)?;
+        self.require_band(is_hb, BandState::Up, "open_rx")?;
+        let (cmd, args) = self.profile.open_rx(is_hb, config);
+        self.ate_cmd(&cmd, args)?;
+        self.set_band_state(is_hb, BandState::RxOpen);
         Ok(())
     }
 
     pub fn close_rx(&mut self, is_hb: Band) -> anyhow::Result<()> {
-        let args = if is_hb == Band::HB {
-            "wlan0 fastconfig -k"
-        } else {
-            "wlan1 fastconfig -k"
+        self.require_band(is_hb, BandState::Up, "close_rx")?;
+        let (cmd, args) = self.profile.close_rx(is_hb);
+        self.ate_cmd(&cmd, args)?;
+        if self.band_state(is_hb) > BandState::Up {
+            self.set_band_state(is_hb, BandState::Up);
         }
-            .trim()
-            .split(" ")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        let cmd = DumpCommand::ATECmd{cmd: "ate_cmd".into(), args};
-        self.send_cmd(cmd)?;
-        self.handle_resp()?;
         Ok(())
     }
 
-    pub fn run_test(&mut self, band: TestBand) {
+    /// Shut down every band but `band`, recording each one in `shut_down` so
+    /// `restore_bands` can bring it back even if a later one fails.
+    pub fn isolate_band(&mut self, band: Band, shut_down: &mut Vec<Band>) -> anyhow::Result<()> {
+        for other in self.profile.bands() {
+            if other != band && self.band_state(other) != BandState::Unbound {
+                self.shut_down_band(other)?;
+                shut_down.push(other);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the bands of `isolate_band` back up. Every band is tried; the
+    /// first error is returned.
+    pub fn restore_bands(&mut self, shut_down: &mut Vec<Band>) -> anyhow::Result<()> {
+        let mut res = Ok(());
+        while let Some(band) = shut_down.pop() {
+            if let Err(e) = self.shut_up_band(band) {
+                log::error!("Could not bring {} back up: {:#}", band, e);
+                res = res.and(Err(e));
+            }
+        }
+        res
+    }
+
+    /// Called between sweep points; an error stops the sweep.
+    pub fn set_interrupt_check(&mut self, check: impl Fn() -> anyhow::Result<()> + Send + Sync + 'static) {
+        self.interrupt = Some(Box::new(check));
+    }
+
+    pub fn check_interrupt(&self) -> anyhow::Result<()> {
+        match &self.interrupt {
+            Some(check) => check(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn band_state(&self, band: Band) -> BandState {
+        self.bands.get(&band).copied().unwrap_or(BandState::Unknown)
+    }
+
+    fn set_band_state(&mut self, band: Band, state: BandState) {
+        log::debug!("{} {} -> {}", band, self.band_state(band), state);
+        self.bands.insert(band, state);
+    }
+
+    /// Fail unless `band` has reached `needed`, or with `auto_transitions`
+    /// walk it there. Bands in `Unknown` state pass unchecked.
+    pub fn require_band(&mut self, band: Band, needed: BandState, action: &str) -> anyhow::Result<()> {
+        self.check_band(band)?;
+        loop {
+            let state = self.band_state(band);
+            if state == BandState::Unknown || state >= needed {
+                return Ok(());
+            }
+            let next = match state {
+                BandState::Unbound | BandState::Bound => "shut_up_band",
+                BandState::Up => "open_rx",
+                _ => "fix_gain",
+            };
+            if !self.options.auto_transitions || state == BandState::RxOpen {
+                return Err(anyhow!("{} on {} needs the band {}, but it is {}; call {} first",
+                    action, band, needed, state, next));
+            }
+            log::info!("{} on {} needs the band {}, running {}", action, band, needed, next);
+            match state {
+                BandState::Unbound => self.shut_up_band(band)?,
+                BandState::Bound => self.iface_up(band)?,
+                _ => self.open_rx(band)?,
+            }
+        }
+    }
+
+    pub fn run_test(&mut self, band: TestBand) -> SweepReport {
         band.run_test(self)
     }
+
+    pub fn run_channel_sweep(&mut self, sweep: ChannelSweep) -> SweepReport {
+        sweep.run_test(self)
+    }
 }
 
-fn pack_bit(a: u8, b: u8, c:u8) -> u16 {
-    let bit1 = a & 0b0000_0001;
-    let bit2 = b & 0b0000_0111;
-    let bit3 = c & 0b0001_1111;
+/// `(index, link target)` of each `phyN -> ...` line of `ls -l`.
+fn parse_phy_links(listing: &str) -> Vec<(u8, String)> {
+    listing.lines()
+        .filter_map(|line| {
+            let (name, target) = line.split_once(" -> ")?;
+            let idx = name.split_whitespace().last()?.strip_prefix("phy")?.parse().ok()?;
+            Some((idx, target.trim().to_string()))
+        })
+        .collect()
+}
 
-    ((bit1 as u16) << 8 | (bit2 as u16) << 5 | (bit3 as u16)) << 1 | (1 << 10)
+/// The original gain packing, kept for the tests that check the regmap's
+/// `fix_gain` sequence against it.
+#[cfg(test)]
+pub(crate) fn pack_bit(a: u8, b: u8, c:u8) -> u16 {
+    GainIndex::new(a, b, c).to_word()
 }
 
 
 #[cfg(test)]
 mod test {
-    use crate::client::pack_bit;
+    use crate::client::{pack_bit, parse_phy_links, CmdOutput, Compression, Dut, DutOptions, LEGACY_MAX_TRANSFER_SIZE};
+    use crate::config::{Band, BandState, GainType, IqEngineConfig, TestBand};
+    use crate::mock::{mock_dut, mock_dut_with, GarbledSource, MockBoard, MockServer};
+    use crate::testcase::TestCase;
+
+    #[test]
+    fn test_parse_phy_links() {
+        let listing = "total 0\n\
+            lrwxrwxrwx 1 root root 0 Jan  1 00:00 phy2 -> ../../devices/platform/30000000.wmac/ieee80211/phy2\n\
+            lrwxrwxrwx 1 root root 0 Jan  1 00:00 phy3 -> ../../devices/platform/20000000.wmac/ieee80211/phy3\n";
+        assert_eq!(parse_phy_links(listing), vec![
+            (2, "../../devices/platform/30000000.wmac/ieee80211/phy2".to_string()),
+            (3, "../../devices/platform/20000000.wmac/ieee80211/phy3".to_string()),
+        ]);
+    }
 
     #[test]
     fn tset_pack_bit() {
         println!("0x{:08X}", pack_bit(1, 0, 1));
     }
+
+    #[test]
+    fn test_handshake_capabilities() {
+        let server = MockServer::bind("127.0.0.1:0").unwrap();
+        let board = server.board();
+        let addr = server.spawn().unwrap();
+
+        let dut = Dut::connect(&addr.to_string()).unwrap();
+        assert_eq!(dut.capabilities().chip_model, "mock");
+        assert!(dut.capabilities().supports("Hello"));
+
+        board.lock().unwrap().capabilities = None;
+        board.lock().unwrap().regs.clear();
+        let mut dut = Dut::connect(&addr.to_string()).unwrap();
+        assert_eq!(dut.capabilities().server_version, "unknown");
+        assert_eq!(dut.capabilities().max_transfer_size, LEGACY_MAX_TRANSFER_SIZE);
+        dut.fix_gain(Band::HB, 0, 0, 0).unwrap();
+        assert!(board.lock().unwrap().regs.contains_key(&0x30c02f88));
+    }
+
+    #[test]
+    fn test_phys_follow_rebinding() {
+        let (board, mut dut, _dump_dir) = mock_dut("phys");
+        assert_eq!(dut.phy(Band::HB).unwrap(), 1);
+        dut.shut_down_band(Band::HB).unwrap();
+        dut.shut_down_band(Band::LB).unwrap();
+        assert!(dut.phy(Band::LB).is_err());
+        dut.shut_up_band(Band::LB).unwrap();
+        dut.shut_up_band(Band::HB).unwrap();
+        dut.open_rx(Band::HB).unwrap();
+        dut.set_iq_engine(Band::HB, Some(IqEngineConfig { decimation: 1, ..IqEngineConfig::new(0x4000) })).unwrap();
+        assert!(dut.set_iq_engine(Band::HB, Some(IqEngineConfig::new(0))).is_err());
+        dut.dump_iq(Band::HB, "HB_iq_0_0_00.txt".to_string()).unwrap();
+
+        assert_eq!(dut.phy(Band::LB).unwrap(), 2);
+        assert_eq!(dut.phy(Band::HB).unwrap(), 3);
+        let board = board.lock().unwrap();
+        assert!(board.shell_log.iter()
+            .any(|cmd| cmd == "echo 0 1 0 15 0 4000 1 2 0 1 0 0 0 > /sys/kernel/debug/ieee80211/phy3/siwifi/iq_engine"));
+    }
+
+    #[test]
+    fn test_band_lifecycle() {
+        let (board, mut dut, _dump_dir) = mock_dut("lifecycle");
+        dut.shut_down_band(Band::LB).unwrap();
+        let err = dut.open_rx(Band::LB).unwrap_err();
+        assert!(err.to_string().contains("call shut_up_band first"), "{}", err);
+        TestBand::new(Band::LB, GainType::Vga((0..2).collect())).run_test(&mut dut);
+        assert!(dut.file_list.file_list.is_empty());
+
+        dut.set_options(DutOptions { auto_transitions: true, ..DutOptions::default() }).unwrap();
+        TestBand::new(Band::LB, GainType::Vga((0..2).collect())).run_test(&mut dut);
+        assert_eq!(dut.file_list.file_list.len(), 2);
+        assert_eq!(dut.band_state(Band::LB), BandState::GainFixed);
+        dut.close_rx(Band::LB).unwrap();
+        assert_eq!(dut.band_state(Band::LB), BandState::Up);
+        assert_eq!(board.lock().unwrap().ate_log, vec![
+            "ifconfig wlan1 up", "ate_cmd wlan1 fastconfig -f 2412 -c 2412 -w 1 -u 1 -r", "ate_cmd wlan1 fastconfig -k",
+        ]);
+    }
+
+    #[test]
+    fn test_copy_retries_on_checksum_mismatch() {
+        let (board, mut dut, dump_dir) = mock_dut("crc");
+        board.lock().unwrap().corrupt_after = Some(1);
+        dut.dump_iq(Band::LB, "LB_iq_0_0_00.txt".into()).unwrap();
+        assert!(dut.copy_files("LB_iq_0_0_00.txt".into()).unwrap());
+
+        let saved = std::fs::read(dump_dir.join("LB_iq_0_0_00.txt")).unwrap();
+        assert_eq!(&saved, board.lock().unwrap().files.get("LB_iq_0_0_00.txt").unwrap());
+    }
+
+    #[test]
+    fn test_copy_resumes_compressed_transfer() {
+        let (board, mut dut, dump_dir) = mock_dut("resume");
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            dut.set_options(DutOptions { compression, ..DutOptions::default() }).unwrap();
+            dut.dump_iq(Band::HB, "HB_iq_0_0_00.txt".into()).unwrap();
+            board.lock().unwrap().truncate_after = Some(1);
+            assert!(dut.copy_files("HB_iq_0_0_00.txt".into()).unwrap());
+
+            let saved = std::fs::read(dump_dir.join("HB_iq_0_0_00.txt")).unwrap();
+            assert_eq!(&saved, board.lock().unwrap().files.get("HB_iq_0_0_00.txt").unwrap(), "{}", compression);
+            assert!(!dump_dir.join("HB_iq_0_0_00.txt.part").exists());
+        }
+    }
+
+    #[test]
+    fn test_copy_resumes_part_left_by_earlier_call() {
+        let (board, mut dut, dump_dir) = mock_dut("resume_part");
+        dut.dump_iq(Band::HB, "HB_iq_0_0_00.txt".into()).unwrap();
+        let full = board.lock().unwrap().files["HB_iq_0_0_00.txt"].clone();
+        // marker bytes instead of the real head show that the copy continued from the `.part`
+        let half = full.len() / 2;
+        std::fs::write(dump_dir.join("HB_iq_0_0_00.txt.part"), vec![b'x'; half]).unwrap();
+        assert!(dut.copy_files("HB_iq_0_0_00.txt".into()).unwrap());
+
+        let saved = std::fs::read(dump_dir.join("HB_iq_0_0_00.txt")).unwrap();
+        assert_eq!(saved.len(), full.len());
+        assert!(saved[..half].iter().all(|&b| b == b'x'));
+        assert_eq!(saved[half..], full[half..]);
+
+        // a new capture under the same name starts over
+        std::fs::write(dump_dir.join("HB_iq_0_0_00.txt.part"), vec![b'x'; half]).unwrap();
+        dut.dump_iq(Band::HB, "HB_iq_0_0_00.txt".into()).unwrap();
+        assert!(dut.copy_files("HB_iq_0_0_00.txt".into()).unwrap());
+        assert_eq!(std::fs::read(dump_dir.join("HB_iq_0_0_00.txt")).unwrap(), full);
+    }
+
+    #[test]
+    fn test_command_output() {
+        let (board, mut dut, _dump_dir) = mock_dut("cmd_output");
+        board.lock().unwrap().cmd_outputs.insert("ifconfig wlan0 up".into(), CmdOutput {
+            exit_code: Some(1),
+            stderr: "ifconfig: SIOCSIFFLAGS: No such device\n".into(),
+            ..CmdOutput::default()
+        });
+        board.lock().unwrap().cmd_outputs.insert("cat /proc/chipid".into(), CmdOutput {
+            exit_code: Some(0),
+            stdout: "0x7621\n".into(),
+            ..CmdOutput::default()
+        });
+        let out = dut.shell_cmd("cat /proc/chipid").unwrap();
+        assert!(out.success());
+        assert_eq!(out.stdout.trim(), "0x7621");
+        let out = dut.ate_cmd("ifconfig", vec!["wlan0".into(), "up".into()]).unwrap();
+        assert!(!out.success());
+        assert_eq!(out.exit_code, Some(1));
+        assert!(out.stderr.contains("No such device"));
+    }
+
+    #[test]
+    fn test_verify_writes() {
+        let server = MockServer::bind("127.0.0.1:0").unwrap();
+        let board = server.board();
+        board.lock().unwrap().read_only.insert(0x04e00478);
+        let addr = server.spawn().unwrap();
+
+        let mut dut = Dut::connect(&addr.to_string()).unwrap();
+        dut.set_options(DutOptions { verify_writes: true, ..DutOptions::default() }).unwrap();
+        dut.fix_gain(Band::HB, 1, 2, 3).unwrap();
+        assert_eq!(dut.read_reg(0x30c02f88).unwrap(), board.lock().unwrap().regs[&0x30c02f88]);
+
+        let err = dut.shut_down_band(Band::LB).unwrap_err();
+        assert!(err.to_string().contains("0x04e00478"), "{}", err);
+
+        board.lock().unwrap().regs.insert(0x20c02f88, 0xffff_0000);
+        dut.write_field("agc_override_LB", "lo_vga", 9).unwrap();
+        dut.write_field("agc_override_LB", "hi_fem", 0).unwrap();
+        assert_eq!(board.lock().unwrap().regs[&0x20c02f88], 0xfdff_0012);
+        assert_eq!(dut.read_field("agc_override_LB", "lo_vga").unwrap(), 9);
+        assert!(dut.write_field("agc_override_LB", "lo_lna", 8).is_err());
+
+        board.lock().unwrap().capabilities = None;
+        let mut dut = Dut::connect(&addr.to_string()).unwrap();
+        assert!(dut.read_reg(0x30c02f88).unwrap_err().to_string().contains("does not support GetReg"));
+    }
+
+    #[test]
+    fn test_write_field_reads_current_value() {
+        let (board, mut dut, _dir) = mock_dut("field_rmw");
+        dut.write_field("agc_override_LB", "lo_vga", 9).unwrap();
+        // changed behind the client's back, e.g. by a restore or a reboot
+        board.lock().unwrap().regs.insert(0x20c02f88, 0xffff_0000);
+        dut.write_field("agc_override_LB", "lo_vga", 9).unwrap();
+        assert_eq!(board.lock().unwrap().regs[&0x20c02f88], 0xffff_0012);
+
+        // a rejected write fails and is not taken as the register's value
+        board.lock().unwrap().rejected.insert(0x20c02f88);
+        let err = dut.write_reg(0x20c02f88, 0x1234).unwrap_err();
+        assert!(err.to_string().contains("0x20c02f88"), "{}", err);
+        assert_eq!(dut.reg_shadow[&0x20c02f88], 0xffff_0012);
+    }
+
+    #[test]
+    fn test_capture_garbled_body() {
+        let (_board, mut dut, _dir) = mock_dut_with("garbled", MockBoard::new(Box::new(GarbledSource)));
+        let err = dut.capture(Band::HB, "HB_iq_0_0_00.txt", false).unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+    }
 }
 
 #[pyclass]
 pub struct PyDut {
-    dut: Dut
+    dut: Dut,
+    /// KeyboardInterrupt caught between sweep points, re-raised once the sweep
+    /// has cleaned up.
+    interrupted: Arc<Mutex<Option<PyErr>>>,
 }
 
 #[pymethods]
 impl PyDut {
     #[new]
     fn new(addr: String) -> PyResult<Self> {
+        let mut dut = Dut::connect(&addr)
+            .map_err(|e| PyConnectionError::new_err(format!("{:#}", e)))?;
+        let interrupted = Arc::new(Mutex::new(None));
+        dut.set_interrupt_check(python_signals(interrupted.clone()));
         Ok(PyDut {
-            dut: Dut::new(&addr)
+            dut,
+            interrupted,
         })
     }
 
-    fn ate_init(&mut self) -> PyResult<()> {
-        self.dut.ate_init().unwrap();
+    #[pyo3(signature = (read_timeout=30.0, write_timeout=10.0, heartbeat_interval=60.0, max_retries=3, retry_delay=2.0, compression="none", verify_writes=false, restore_registers=false, auto_transitions=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn set_options(&mut self, read_timeout: Option<f64>, write_timeout: Option<f64>, heartbeat_interval: Option<f64>,
+                   max_retries: u32, retry_delay: f64, compression: &str, verify_writes: bool,
+                   restore_registers: bool, auto_transitions: bool) -> PyResult<()> {
+        let seconds = |name: &str, secs: f64| Duration::try_from_secs_f64(secs)
+            .map_err(|_| PyValueError::new_err(format!("{} must be a non-negative number of seconds, got {}", name, secs)));
+        let options = DutOptions {
+            read_timeout: read_timeout.map(|secs| seconds("read_timeout", secs)).transpose()?,
+            write_timeout: write_timeout.map(|secs| seconds("write_timeout", secs)).transpose()?,
+            heartbeat_interval: heartbeat_interval.map(|secs| seconds("heartbeat_interval", secs)).transpose()?,
+            max_retries,
+            retry_delay: seconds("retry_delay", retry_delay)?,
+            compression: compression.parse()
+                .map_err(|_| PyValueError::new_err(format!("unknown compression {}", compression)))?,
+            verify_writes,
+            restore_registers,
+            auto_transitions,
+        };
+        self.dut.set_options(options).map_err(runtime_error)
+    }
+
+    /// Inclusive `(start, end)` address pairs snapshotted by default.
+    fn set_snapshot_ranges(&mut self, ranges: Vec<(u32, u32)>) -> PyResult<()> {
+        self.dut.set_snapshot_ranges(ranges.into_iter().map(|(start, end)| start..=end).collect());
         Ok(())
     }
 
-    fn shut_down_band(&mut self, band_5g: String) -> PyResult<()> {
-        let band = if band_5g == "HB" {
-            HB
-        } else {
-            LB
+    #[pyo3(signature = (path, ranges=None))]
+    fn snapshot_regs(&mut self, path: String, ranges: Option<Vec<(u32, u32)>>) -> PyResult<()> {
+        let ranges = match ranges {
+            Some(ranges) => ranges.into_iter().map(|(start, end)| start..=end).collect(),
+            None => self.dut.snapshot_ranges(),
         };
-        self.dut.shut_down_band(band).unwrap();
+        self.dut.snapshot_regs(&ranges).map_err(runtime_error)?
+            .save(path).map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    fn restore_regs(&mut self, path: String) -> PyResult<usize> {
+        let snapshot = RegSnapshot::load(path)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))?;
+        self.dut.restore_regs(&snapshot).map_err(runtime_error)
+    }
+
+    /// Look the phy index of every band up on the board again, e.g. after
+    /// rebinding a driver by hand.
+    fn discover_phys(&mut self) -> PyResult<HashMap<String, u8>> {
+        let phys = self.dut.discover_phys().map_err(runtime_error)?;
+        Ok(phys.iter().map(|(band, idx)| (band.to_string(), *idx)).collect())
+    }
+
+    /// Target another SoC described by a profile JSON file.
+    fn load_profile(&mut self, path: String) -> PyResult<()> {
+        let profile = GenericProfile::load(&path)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))?;
+        self.dut.set_profile(Box::new(profile));
         Ok(())
     }
 
-    fn open_rx(&mut self, band: String) -> PyResult<()> {
-        let band = if band == "HB" {
-            HB
-        } else {
-            LB
-        };
-        self.dut.open_rx(band).unwrap();
+    fn load_regmap(&mut self, path: String) -> PyResult<()> {
+        self.dut.load_regmap(&path)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    fn read_field(&mut self, reg: String, field: String) -> PyResult<u32> {
+        self.dut.read_field(&reg, &field)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    fn write_field(&mut self, reg: String, field: String, value: u32) -> PyResult<()> {
+        self.dut.write_field(&reg, &field, value)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    #[pyo3(signature = (name, params=HashMap::new()))]
+    fn run_sequence(&mut self, name: String, params: HashMap<String, Bound<'_, PyAny>>) -> PyResult<()> {
+        let params = params.into_iter()
+            .map(|(k, v)| Ok((k, v.str()?.to_string())))
+            .collect::<PyResult<HashMap<_, _>>>()?;
+        self.dut.run_sequence(&name, &params)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    fn read_reg(&mut self, addr: u32) -> PyResult<u32> {
+        self.dut.read_reg(addr).map_err(runtime_error)
+    }
+
+    fn write_reg(&mut self, addr: u32, value: u32) -> PyResult<()> {
+        self.dut.write_reg(addr, value).map_err(runtime_error)
+    }
+
+    fn shell_cmd(&mut self, cmd: String) -> PyResult<CmdOutput> {
+        self.dut.shell_cmd(&cmd).map_err(runtime_error)
+    }
+
+    #[pyo3(signature = (cmd, args=Vec::new()))]
+    fn ate_cmd(&mut self, cmd: String, args: Vec<String>) -> PyResult<CmdOutput> {
+        self.dut.ate_cmd(&cmd, args).map_err(runtime_error)
+    }
+
+    fn capabilities(&self) -> PyResult<Capabilities> {
+        Ok(self.dut.capabilities().clone())
+    }
+
+    fn heartbeat(&mut self) -> PyResult<()> {
+        self.dut.heartbeat().map_err(runtime_error)
+    }
+
+    fn set_dump_dir(&mut self, dir: String) -> PyResult<()> {
+        self.dut.set_dump_dir(dir);
         Ok(())
     }
 
-    fn run_test(&mut self, band: String, gain: String, v: Vec<u8>) -> PyResult<()> {
-        let min = v.iter().min().unwrap();
-        let max = v.iter().max().unwrap();
-        let test = match (band.as_str(), gain.as_str()) {
-            ("HB", "Fem") => {
-                TestBand::HB(Fem(*min..max+1))
-            }
-            ("HB", "Lna") => {
-                TestBand::HB(Lna(*min..max+1))
-            }
-            ("HB", "Vga") => {
-                TestBand::HB(Vga(*min..max+1))
-            }
-            ("LB", "Fem") => {
-                TestBand::LB(Fem(*min..max+1))
-            }
-            ("LB", "Lna") => {
-                TestBand::LB(Lna(*min..max+1))
-            }
-            ("LB", "Vga") => {
-                TestBand::LB(Vga(*min..max+1))
-            }
-            _ => {
-                log::warn!("no test match with {} {}", band, gain);
-                return Ok(())
-            }
+    fn ate_init(&mut self) -> PyResult<()> {
+        self.dut.ate_init().map_err(runtime_error)
+    }
+
+    fn shut_down_band(&mut self, band_5g: String) -> PyResult<()> {
+        let band = self.parse_band(&band_5g)?;
+        self.dut.shut_down_band(band).map_err(runtime_error)
+    }
+
+    /// Without arguments the band's default channel, 20 MHz wide.
+    #[pyo3(signature = (band, freq=None, center=None, bw=20, extra=Vec::new()))]
+    fn open_rx(&mut self, band: String, freq: Option<u32>, center: Option<u32>, bw: u32, extra: Vec<String>) -> PyResult<()> {
+        let band = self.parse_band(&band)?;
+        let config = self.rx_config(band, freq, center, bw, extra)?;
+        self.dut.open_rx_with(band, &config).map_err(runtime_error)
+    }
+
+    /// Sweep one stage over the indexes in `v`, in order; the other stages stay at 0.
+    /// Each point is captured `repeats` times. Returns a `PointResult` per capture.
+    #[pyo3(signature = (band, gain, v, repeats=1))]
+    fn run_test(&mut self, band: String, gain: String, v: Vec<u8>, repeats: u32) -> PyResult<Vec<PointResult>> {
+        let test = test_band(self.parse_band(&band)?, &gain, v, repeats)?;
+        let report = self.dut.run_test(test);
+        self.take_interrupt()?;
+        Ok(report.points)
+    }
+
+    /// Sweep every fem × lna × vga combination of the lists, or exactly the
+    /// `(fem, lna, vga)` tuples in `points`. Stages without a list stay at `base`.
+    #[pyo3(signature = (band, fem=Vec::new(), lna=Vec::new(), vga=Vec::new(), base=(0, 0, 0), points=None, repeats=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn run_sweep(&mut self, band: String, fem: Vec<u8>, lna: Vec<u8>, vga: Vec<u8>, base: (u8, u8, u8),
+                 points: Option<Vec<(u8, u8, u8)>>, repeats: u32) -> PyResult<Vec<PointResult>> {
+        let band = self.parse_band(&band)?;
+        let sweep = match points {
+            Some(points) => GainSweep::Points(points.into_iter()
+                .map(|(fem, lna, vga)| GainIndex::new(fem, lna, vga))
+                .collect()),
+            None => GainSweep::cartesian(fem, lna, vga).with_base(GainIndex::new(base.0, base.1, base.2)),
         };
-        self.dut.run_test(test);
-        Ok(())
+        let test = TestBand::new(band, sweep).with_repeats(repeats);
+        test.validate().map_err(|e| PyValueError::new_err(format!("{:#}", e)))?;
+        let report = self.dut.run_test(test);
+        self.take_interrupt()?;
+        Ok(report.points)
     }
 
-    fn close_rx(&mut self, is_hb: String) -> PyResult<()> {
-        let band = if is_hb == "HB" {
-            HB
-        } else {
-            LB
+    /// Run the gain sweep on every channel in `channels`; an entry is a
+    /// primary frequency, or a `(freq, center)` pair for wider channels.
+    #[pyo3(signature = (band, gain, v, channels, bw=20, extra=Vec::new(), repeats=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn run_channel_sweep(&mut self, band: String, gain: String, v: Vec<u8>, channels: Vec<Channel>, bw: u32,
+                         extra: Vec<String>, repeats: u32) -> PyResult<Vec<PointResult>> {
+        let band = self.parse_band(&band)?;
+        let test = test_band(band, &gain, v, repeats)?;
+        let channels = channels.into_iter()
+            .map(|ch| {
+                let (freq, center) = match ch {
+                    Channel::Freq(freq) => (freq, None),
+                    Channel::WithCenter(freq, center) => (freq, Some(center)),
+                };
+                self.rx_config(band, Some(freq), center, bw, extra.clone())
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let report = self.dut.run_channel_sweep(ChannelSweep { gain: test, channels });
+        self.take_interrupt()?;
+        Ok(report.points)
+    }
+
+    /// Bands of the current profile.
+    fn bands(&self) -> Vec<String> {
+        self.dut.bands().iter().map(|band| band.to_string()).collect()
+    }
+
+    fn take_interrupt(&self) -> PyResult<()> {
+        match self.interrupted.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// `with dut.band("LB"):` runs the block with every other band shut down
+    /// and brings them back up afterwards, also on errors and Ctrl-C.
+    fn band(slf: Bound<'_, Self>, band: String) -> PyResult<BandContext> {
+        let band = slf.borrow().parse_band(&band)?;
+        Ok(BandContext { dut: slf.unbind(), band, shut_down: Vec::new() })
+    }
+
+    /// `with dut.rx("HB", freq=5500):` opens RX for the block and closes it afterwards.
+    #[pyo3(signature = (band, freq=None, center=None, bw=20, extra=Vec::new()))]
+    fn rx(slf: Bound<'_, Self>, band: String, freq: Option<u32>, center: Option<u32>, bw: u32, extra: Vec<String>)
+        -> PyResult<RxContext> {
+        let (band, config) = {
+            let this = slf.borrow();
+            let band = this.parse_band(&band)?;
+            (band, this.rx_config(band, freq, center, bw, extra)?)
         };
-        self.dut.close_rx(band).unwrap();
-        Ok(())
+        Ok(RxContext { dut: slf.unbind(), band, config, opened: false })
+    }
+
+    fn close_rx(&mut self, is_hb: String) -> PyResult<()> {
+        let band = self.parse_band(&is_hb)?;
+        self.dut.close_rx(band).map_err(runtime_error)
     }
 
     fn shut_up_band(&mut self, band_5g: String) -> PyResult<()> {
-        let band = if band_5g == "HB" {
-            HB
-        } else {
-            LB
-        };
-        self.dut.shut_up_band(band).unwrap();
-        Ok(())
+        let band = self.parse_band(&band_5g)?;
+        self.dut.shut_up_band(band).map_err(runtime_error)
     }
 
-    fn parse(&mut self) -> PyResult<()> {
+    #[pyo3(signature = (band, file_name, archive=false))]
+    fn capture(&mut self, band: String, file_name: String, archive: bool) -> PyResult<IqCapture> {
+        let band = self.parse_band(&band)?;
+        self.dut.capture(band, &file_name, archive).map_err(runtime_error)
+    }
+
+    fn iq_engine(&self, band: String) -> PyResult<IqEngineConfig> {
+        Ok(self.dut.iq_engine(self.parse_band(&band)?))
+    }
+
+    /// Capture settings for later dumps on `band`; `None` restores the default.
+    #[pyo3(signature = (band, config=None))]
+    fn set_iq_engine(&mut self, band: String, config: Option<IqEngineConfig>) -> PyResult<()> {
+        self.dut.set_iq_engine(self.parse_band(&band)?, config)
+            .map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    fn band_state(&self, band: String) -> PyResult<String> {
+        let band = self.parse_band(&band)?;
+        Ok(self.dut.band_state(band).to_string())
+    }
+
+    /// Write `result.xlsx` into the dump dir; repeated points whose power spreads more
+    /// than `spread_threshold` dB are flagged on the `_stats` sheets.
+    #[pyo3(signature = (spread_threshold=DEFAULT_SPREAD_THRESHOLD))]
+    fn parse(&mut self, spread_threshold: f64) -> PyResult<()> {
+        check_spread_threshold(spread_threshold).map_err(|e| PyValueError::new_err(format!("{:#}", e)))?;
         let file_list = self.dut.file_list.file_list.clone();
         FileParser::new(file_list)
+            .with_metrics(self.dut.file_list.metrics.clone())
+            .with_bands(self.dut.bands())
+            .with_spread_threshold(spread_threshold)
             .sort_file()
-            .parse_and_write().unwrap();
-        Ok(())
+            .parse_and_write(self.dut.dump_dir())
+            .map_err(|e| PyOSError::new_err(format!("{:#}", e)))
+    }
+
+}
+
+impl PyDut {
+    /// A band of the profile; unknown names raise ValueError.
+    fn parse_band(&self, band: &str) -> PyResult<Band> {
+        self.dut.band(band).map_err(|e| PyValueError::new_err(format!("{:#}", e)))
+    }
+
+    fn rx_config(&self, band: Band, freq: Option<u32>, center: Option<u32>, bw: u32, extra: Vec<String>)
+        -> PyResult<RxConfig> {
+        let value_error = |e: anyhow::Error| PyValueError::new_err(format!("{:#}", e));
+        let plan = self.dut.band_plan(band).map_err(value_error)?;
+        let config = RxConfig {
+            freq: freq.unwrap_or(plan.default_freq),
+            center,
+            bandwidth: Bandwidth::from_mhz(bw).map_err(value_error)?,
+            extra_args: extra,
+        };
+        config.validate(band, &plan).map_err(value_error)?;
+        Ok(config)
+    }
+}
+
+/// Interrupt check stopping a sweep on Ctrl-C; the KeyboardInterrupt is left
+/// in `slot` to be raised once the sweep has cleaned up. Until then every
+/// later check fails too, so the steps of a plan after the sweep do not run.
+pub(crate) fn python_signals(slot: Arc<Mutex<Option<PyErr>>>) -> impl Fn() -> anyhow::Result<()> + Send + Sync {
+    move || {
+        if slot.lock().unwrap().is_some() {
+            return Err(anyhow!("Interrupted"));
+        }
+        Python::attach(|py| py.check_signals()).map_err(|e| {
+            *slot.lock().unwrap() = Some(e);
+            anyhow!("Interrupted")
+        })
+    }
+}
+
+/// Board and link errors are expected in scripts, raise them as RuntimeError instead of panicking.
+fn runtime_error(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", e))
+}
+
+#[derive(FromPyObject)]
+enum Channel {
+    Freq(u32),
+    WithCenter(u32, u32),
+}
+
+fn test_band(band: Band, gain: &str, v: Vec<u8>, repeats: u32) -> PyResult<TestBand> {
+    let value_error = |e: anyhow::Error| PyValueError::new_err(format!("{:#}", e));
+    let test = TestBand::new(band, GainType::from_name(gain, v).map_err(value_error)?).with_repeats(repeats);
+    test.validate().map_err(value_error)?;
+    Ok(test)
+}
 
+#[pyclass]
+pub struct BandContext {
+    dut: Py<PyDut>,
+    band: Band,
+    shut_down: Vec<Band>,
+}
+
+#[pymethods]
+impl BandContext {
+    fn __enter__(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let this = &mut *slf;
+        let mut dut = this.dut.borrow_mut(py);
+        if let Err(e) = dut.dut.isolate_band(this.band, &mut this.shut_down) {
+            let _ = dut.dut.restore_bands(&mut this.shut_down);
+            return Err(runtime_error(e));
+        }
+        drop(dut);
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (*_exc))]
+    fn __exit__(&mut self, py: Python<'_>, _exc: Bound<'_, PyTuple>) -> PyResult<bool> {
+        self.dut.borrow_mut(py).dut.restore_bands(&mut self.shut_down).map_err(runtime_error)?;
+        Ok(false)
     }
+}
+
+#[pyclass]
+pub struct RxContext {
+    dut: Py<PyDut>,
+    band: Band,
+    config: RxConfig,
+    opened: bool,
+}
 
-}
\ No newline at end of file
+#[pymethods]
+impl RxContext {
+    fn __enter__(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        let py = slf.py();
+        let this = &mut *slf;
+        this.dut.borrow_mut(py).dut.open_rx_with(this.band, &this.config).map_err(runtime_error)?;
+        this.opened = true;
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (*_exc))]
+    fn __exit__(&mut self, py: Python<'_>, _exc: Bound<'_, PyTuple>) -> PyResult<bool> {
+        if std::mem::take(&mut self.opened) {
+            self.dut.borrow_mut(py).dut.close_rx(self.band).map_err(runtime_error)?;
+        }
+        Ok(false)
+    }
+}
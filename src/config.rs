@@ -1,83 +1,515 @@
-use std::ops::Range;
-use std::sync::{LazyLock, Mutex};
-use pyo3::pyclass;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Mutex;
+use anyhow::{anyhow, Context};
+use pyo3::exceptions::PyValueError;
+use pyo3::{pyclass, pymethods, PyResult};
 use strum::Display;
 
-#[derive(PartialEq, Eq, Debug, Display, Clone, Copy)]
-pub enum Band {
-    HB,
-    LB
+/// A radio of the board, named as in dump file names and profiles: `HB` (5 GHz),
+/// `LB` (2.4 GHz), or whatever else the chip profile declares, e.g. `6G`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct Band(&'static str);
+
+/// Names of the bands created at run time; interned so `Band` stays `Copy`.
+static BAND_NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+impl Band {
+    pub const HB: Band = Band("HB");
+    pub const LB: Band = Band("LB");
+
+    pub fn name(self) -> &'static str {
+        self.0
+    }
+}
+
+impl FromStr for Band {
+    type Err = anyhow::Error;
+
+    /// Names end up in file and sheet names, so `_` and blanks are rejected.
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        if name.is_empty() || name.contains(|c: char| c == '_' || c.is_whitespace()) {
+            return Err(anyhow!("Invalid band name {:?}", name));
+        }
+        for band in [Band::HB, Band::LB] {
+            if band.0 == name {
+                return Ok(band);
+            }
+        }
+        let mut names = BAND_NAMES.lock().unwrap();
+        let name = match names.iter().find(|known| **known == name) {
+            Some(known) => *known,
+            None => {
+                let leaked: &'static str = Box::leak(name.to_string().into_boxed_str());
+                names.push(leaked);
+                leaked
+            }
+        };
+        Ok(Band(name))
+    }
 }
 
-pub enum TestBand {
-    HB(GainType),
-    LB(GainType)
+impl fmt::Display for Band {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
 }
 
-impl TestBand {
-    pub fn return_gain_type(&self) -> GainType {
+/// Channels a band can be tuned to.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct BandPlan {
+    pub freqs: RangeInclusive<u32>,
+    pub max_bandwidth: Bandwidth,
+    /// Primary channel `open_rx` uses when none is given.
+    pub default_freq: u32,
+}
+
+impl BandPlan {
+    /// Plans of the bands known by name: `HB`, `LB` and `6G`.
+    pub fn builtin(band: Band) -> Option<Self> {
+        let (freqs, max_bandwidth, default_freq) = match band.name() {
+            "HB" => (4900..=5900, Bandwidth::Bw160, 5180),
+            "LB" => (2400..=2500, Bandwidth::Bw40, 2412),
+            "6G" => (5925..=7125, Bandwidth::Bw160, 5955),
+            _ => return None,
+        };
+        Some(Self { freqs, max_bandwidth, default_freq })
+    }
+}
+
+/// Bring-up stage of a band, in order. A band the `Dut` has not driven yet is
+/// `Unknown` and is not checked.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Display, Clone, Copy)]
+pub enum BandState {
+    Unknown,
+    Unbound,
+    /// Driver bound, interface still down.
+    Bound,
+    Up,
+    RxOpen,
+    GainFixed,
+}
+
+/// Channel bandwidth of `fastconfig -w`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Bandwidth {
+    #[default]
+    Bw20,
+    Bw40,
+    Bw80,
+    Bw160,
+}
+
+impl Bandwidth {
+    pub fn from_mhz(mhz: u32) -> anyhow::Result<Self> {
+        match mhz {
+            20 => Ok(Bandwidth::Bw20),
+            40 => Ok(Bandwidth::Bw40),
+            80 => Ok(Bandwidth::Bw80),
+            160 => Ok(Bandwidth::Bw160),
+            _ => Err(anyhow!("Unsupported bandwidth {} MHz, expected 20/40/80/160", mhz)),
+        }
+    }
+
+    pub fn mhz(self) -> u32 {
         match self {
-            TestBand::HB(item) => {item.return_type()}
-            TestBand::LB(item) => {item.return_type()}
+            Bandwidth::Bw20 => 20,
+            Bandwidth::Bw40 => 40,
+            Bandwidth::Bw80 => 80,
+            Bandwidth::Bw160 => 160,
         }
     }
 }
 
-pub enum GainType {
-    Fem(Range<u8>),
-    Lna(Range<u8>),
-    Vga(Range<u8>)
+/// Channel `open_rx` tunes the receiver to.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RxConfig {
+    /// Primary 20 MHz channel, in MHz.
+    pub freq: u32,
+    /// Center of the whole channel; the primary channel when `None`.
+    pub center: Option<u32>,
+    pub bandwidth: Bandwidth,
+    /// Further `fastconfig` options, appended as is.
+    pub extra_args: Vec<String>,
 }
 
-impl GainType {
-    pub fn return_iter(&self) -> Range<u8> {
+impl RxConfig {
+    pub fn new(freq: u32) -> Self {
+        Self { freq, center: None, bandwidth: Bandwidth::Bw20, extra_args: Vec::new() }
+    }
+
+    /// The band's default channel, 20 MHz wide.
+    pub fn default_for(plan: &BandPlan) -> Self {
+        Self::new(plan.default_freq)
+    }
+
+    pub fn center(&self) -> u32 {
+        self.center.unwrap_or(self.freq)
+    }
+
+    /// Check the channel lies in `band` and the center fits the bandwidth.
+    pub fn validate(&self, band: Band, plan: &BandPlan) -> anyhow::Result<()> {
+        if !plan.freqs.contains(&self.freq) {
+            return Err(anyhow!("{} MHz is outside band {}", self.freq, band));
+        }
+        if self.bandwidth.mhz() > plan.max_bandwidth.mhz() {
+            return Err(anyhow!("{} supports up to {} MHz, not {} MHz",
+                band, plan.max_bandwidth.mhz(), self.bandwidth.mhz()));
+        }
+        let offset = self.center().abs_diff(self.freq);
+        let fits = match self.bandwidth {
+            Bandwidth::Bw20 => offset == 0,
+            bw => offset % 20 == 10 && offset <= bw.mhz() / 2 - 10,
+        };
+        if !fits {
+            return Err(anyhow!("Center {} MHz does not fit a {} MHz channel with primary {} MHz",
+                self.center(), self.bandwidth.mhz(), self.freq));
+        }
+        Ok(())
+    }
+}
+
+/// Arguments `dump_iq` writes to the `iq_engine` debugfs file, in the order the
+/// siwifi driver reads them.
+#[pyclass(get_all, set_all)]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct IqEngineConfig {
+    /// Trigger source; 0 starts capturing as soon as the engine is armed.
+    pub trigger_mode: u32,
+    /// 1 arms the engine.
+    pub enable: u32,
+    /// Tap point in the RX chain; 0 is the ADC output.
+    pub source: u32,
+    /// Bit mask of the receive paths to capture.
+    pub path_mask: u32,
+    /// Samples skipped after the trigger.
+    pub trigger_delay: u32,
+    /// Capture buffer length, written in hex.
+    pub capture_len: u32,
+    /// Decimation applied before storing; 0 keeps the full rate.
+    pub decimation: u32,
+    /// Bytes per stored I/Q component.
+    pub sample_width: u32,
+    /// 1 keeps recapturing into the buffer instead of stopping when full.
+    pub loop_capture: u32,
+    /// 1 writes the buffer out when the capture completes.
+    pub dump_after: u32,
+    /// Trailing fields the driver accepts but we always leave at 0.
+    pub reserved: [u32; 3],
+}
+
+impl IqEngineConfig {
+    /// Largest capture buffer the engine takes.
+    pub const MAX_CAPTURE_LEN: u32 = 0x20000;
+
+    /// The settings `dump_iq` has always used, with `capture_len` per band.
+    pub fn new(capture_len: u32) -> Self {
+        Self {
+            trigger_mode: 0,
+            enable: 1,
+            source: 0,
+            path_mask: 15,
+            trigger_delay: 0,
+            capture_len,
+            decimation: 0,
+            sample_width: 2,
+            loop_capture: 0,
+            dump_after: 1,
+            reserved: [0; 3],
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, flag) in [("enable", self.enable), ("loop_capture", self.loop_capture), ("dump_after", self.dump_after)] {
+            if flag > 1 {
+                return Err(anyhow!("iq_engine {} must be 0 or 1, not {}", name, flag));
+            }
+        }
+        if self.path_mask == 0 || self.path_mask > 0xf {
+            return Err(anyhow!("iq_engine path_mask 0x{:x} must select 1 to 4 paths", self.path_mask));
+        }
+        if self.capture_len == 0 || self.capture_len > Self::MAX_CAPTURE_LEN {
+            return Err(anyhow!("iq_engine capture_len 0x{:x} must be in 1..=0x{:x}",
+                self.capture_len, Self::MAX_CAPTURE_LEN));
+        }
+        Ok(())
+    }
+
+    /// The line written to `iq_engine`, e.g. `0 1 0 15 0 e000 0 2 0 1 0 0 0`.
+    pub fn to_args(&self) -> String {
+        format!("{} {} {} {} {} {:x} {} {} {} {} {} {} {}",
+                self.trigger_mode, self.enable, self.source, self.path_mask, self.trigger_delay,
+                self.capture_len, self.decimation, self.sample_width, self.loop_capture, self.dump_after,
+                self.reserved[0], self.reserved[1], self.reserved[2])
+    }
+}
+
+#[pymethods]
+impl IqEngineConfig {
+    #[new]
+    #[pyo3(signature = (capture_len, trigger_mode=0, enable=1, source=0, path_mask=15, trigger_delay=0,
+                        decimation=0, sample_width=2, loop_capture=0, dump_after=1))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(capture_len: u32, trigger_mode: u32, enable: u32, source: u32, path_mask: u32, trigger_delay: u32,
+              decimation: u32, sample_width: u32, loop_capture: u32, dump_after: u32) -> PyResult<Self> {
+        let config = Self {
+            trigger_mode, enable, source, path_mask, trigger_delay, capture_len, decimation, sample_width,
+            loop_capture, dump_after, reserved: [0; 3],
+        };
+        config.validate().map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(config)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("IqEngineConfig({})", self.to_args())
+    }
+}
+
+/// Index of each gain stage, as packed into the AGC override word by `to_word`.
+#[pyclass(get_all)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GainIndex {
+    pub fem: u8,
+    pub lna: u8,
+    pub vga: u8,
+}
+
+impl GainIndex {
+    /// Largest index of each stage: 1-bit fem, 3-bit lna, 5-bit vga.
+    pub const MAX: GainIndex = GainIndex { fem: 1, lna: 7, vga: 31 };
+
+    pub fn new(fem: u8, lna: u8, vga: u8) -> Self {
+        Self { fem, lna, vga }
+    }
+
+    /// Reject indexes `to_word` would otherwise silently mask.
+    pub fn validate(self) -> anyhow::Result<()> {
+        for (stage, idx, max) in [("fem", self.fem, Self::MAX.fem), ("lna", self.lna, Self::MAX.lna), ("vga", self.vga, Self::MAX.vga)] {
+            check_index(stage, idx, max)?;
+        }
+        Ok(())
+    }
+
+    /// AGC override half word: fem, lna, vga packed from bit 1, bit 10 set to force the gain.
+    pub fn to_word(self) -> u16 {
+        let fem = self.fem & 0b0000_0001;
+        let lna = self.lna & 0b0000_0111;
+        let vga = self.vga & 0b0001_1111;
+
+        ((fem as u16) << 8 | (lna as u16) << 5 | (vga as u16)) << 1 | (1 << 10)
+    }
+
+    /// Decode an AGC override half word, the inverse of `to_word`.
+    pub fn from_word(word: u16) -> Self {
+        let bits = word >> 1;
+        Self {
+            fem: ((bits >> 8) & 0b1) as u8,
+            lna: ((bits >> 5) & 0b111) as u8,
+            vga: (bits & 0b1_1111) as u8,
+        }
+    }
+
+    /// Dump file name of this gain point: `{prefix}_iq_{fem}_{lna}_{vga:02}.txt`.
+    pub fn file_name(self, prefix: &str) -> String {
+        format!("{}_iq_{}_{}_{:02}.txt", prefix, self.fem, self.lna, self.vga)
+    }
+}
+
+fn check_index(stage: &str, idx: u8, max: u8) -> anyhow::Result<()> {
+    if idx > max {
+        return Err(anyhow!("{} index {} is out of range 0..={}", stage, idx, max));
+    }
+    Ok(())
+}
+
+/// Gain points of a sweep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GainSweep {
+    /// Every combination of the three lists, vga varying fastest. A stage with
+    /// an empty list stays at its `base` value.
+    Cartesian {
+        fem: Vec<u8>,
+        lna: Vec<u8>,
+        vga: Vec<u8>,
+        base: GainIndex,
+    },
+    /// These points, in order.
+    Points(Vec<GainIndex>),
+}
+
+impl GainSweep {
+    pub fn cartesian(fem: Vec<u8>, lna: Vec<u8>, vga: Vec<u8>) -> Self {
+        GainSweep::Cartesian { fem, lna, vga, base: GainIndex::default() }
+    }
+
+    /// Hold the stages that are not swept at `base` instead of 0.
+    pub fn with_base(self, base: GainIndex) -> Self {
+        match self {
+            GainSweep::Cartesian { fem, lna, vga, .. } => GainSweep::Cartesian { fem, lna, vga, base },
+            points => points,
+        }
+    }
+
+    /// Check every index is in range and there is at least one point to sweep.
+    pub fn validate(&self) -> anyhow::Result<()> {
         match self {
-            GainType::Fem(item) => {item.clone()}
-            GainType::Lna(item) => {item.clone()}
-            GainType::Vga(item) => {item.clone()}
+            GainSweep::Cartesian { fem, lna, vga, base } => {
+                if fem.is_empty() && lna.is_empty() && vga.is_empty() {
+                    return Err(anyhow!("Gain sweep has no fem, lna or vga index"));
+                }
+                base.validate().context("base")?;
+                for (stage, list, max) in [("fem", fem, GainIndex::MAX.fem), ("lna", lna, GainIndex::MAX.lna), ("vga", vga, GainIndex::MAX.vga)] {
+                    for &idx in list {
+                        check_index(stage, idx, max)?;
+                    }
+                }
+                Ok(())
+            }
+            GainSweep::Points(points) => {
+                if points.is_empty() {
+                    return Err(anyhow!("Gain sweep has no points"));
+                }
+                points.iter().try_for_each(|point| point.validate())
+            }
         }
     }
 
-    fn return_type(&self) -> Self {
-        use GainType::*;
+    pub fn points(&self) -> Vec<GainIndex> {
         match self {
-            Fem(_) => {Fem(0..0)}
-            Lna(_) => {Lna(0..0)}
-            Vga(_) => {Vga(0..0)}
+            GainSweep::Cartesian { fem, lna, vga, base } => {
+                let or_base = |list: &Vec<u8>, base: u8| if list.is_empty() { vec![base] } else { list.clone() };
+                let (fems, lnas, vgas) = (or_base(fem, base.fem), or_base(lna, base.lna), or_base(vga, base.vga));
+                fems.iter()
+                    .flat_map(|&fem| lnas.iter().map(move |&lna| (fem, lna)))
+                    .flat_map(|(fem, lna)| vgas.iter().map(move |&vga| GainIndex { fem, lna, vga }))
+                    .collect()
+            }
+            GainSweep::Points(points) => points.clone(),
+        }
+    }
+}
+
+/// A sweep of one stage with the others at 0.
+impl From<GainType> for GainSweep {
+    fn from(gain: GainType) -> Self {
+        match gain {
+            GainType::Fem(idx) => GainSweep::cartesian(idx, Vec::new(), Vec::new()),
+            GainType::Lna(idx) => GainSweep::cartesian(Vec::new(), idx, Vec::new()),
+            GainType::Vga(idx) => GainSweep::cartesian(Vec::new(), Vec::new(), idx),
         }
     }
 }
 
-pub struct GlobPhyNum {
-    hb: u8,
-    lb: u8,
+/// A gain sweep on one band.
+pub struct TestBand {
+    pub band: Band,
+    pub gain: GainSweep,
+    /// Captures per gain point.
+    pub repeats: u32,
 }
-static GLOB_PHY_NUM_INSTANCE: LazyLock<Mutex<GlobPhyNum>> = LazyLock::new(|| {
-    Mutex::new(GlobPhyNum{hb: 1, lb: 0})
-});
 
-impl GlobPhyNum {
-    pub fn hb() -> u8 {
-        let num = GLOB_PHY_NUM_INSTANCE.lock().unwrap();
-        num.hb
+impl TestBand {
+    pub fn new(band: Band, gain: impl Into<GainSweep>) -> Self {
+        Self { band, gain: gain.into(), repeats: 1 }
     }
 
-    pub fn lb() -> u8 {
-        let num = GLOB_PHY_NUM_INSTANCE.lock().unwrap();
-        num.lb
+    pub fn with_repeats(mut self, repeats: u32) -> Self {
+        self.repeats = repeats;
+        self
     }
 
-    pub fn add_hb() {
-        let mut num = GLOB_PHY_NUM_INSTANCE.lock().unwrap();
-        let maxnum = num.hb.max(num.lb);
-        num.hb = maxnum+1;
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.repeats == 0 {
+            return Err(anyhow!("repeats must be at least 1"));
+        }
+        self.gain.validate()
     }
+}
 
-    pub fn add_lb() {
-        let mut num = GLOB_PHY_NUM_INSTANCE.lock().unwrap();
-        let maxnum = num.lb.max(num.hb);
-        num.lb = maxnum+1;
+/// Indexes of one stage to sweep, in order, e.g. `Vga((0..=20).step_by(4).collect())`.
+pub enum GainType {
+    Fem(Vec<u8>),
+    Lna(Vec<u8>),
+    Vga(Vec<u8>)
+}
+
+impl GainType {
+    /// `Fem`, `Lna` or `Vga` by name, as used from Python.
+    pub fn from_name(name: &str, idx: Vec<u8>) -> anyhow::Result<Self> {
+        match name {
+            "Fem" => Ok(GainType::Fem(idx)),
+            "Lna" => Ok(GainType::Lna(idx)),
+            "Vga" => Ok(GainType::Vga(idx)),
+            _ => Err(anyhow!("Unknown gain stage {}, expected Fem, Lna or Vga", name)),
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::config::{Band, BandPlan, Bandwidth, GainIndex, GainSweep, GainType, IqEngineConfig, RxConfig};
+
+    #[test]
+    fn test_gain_sweep_points() {
+        let sweep = GainSweep::cartesian(vec![0, 1], Vec::new(), vec![3, 5]).with_base(GainIndex::new(0, 2, 0));
+        assert_eq!(sweep.points(), vec![
+            GainIndex::new(0, 2, 3), GainIndex::new(0, 2, 5), GainIndex::new(1, 2, 3), GainIndex::new(1, 2, 5),
+        ]);
+        assert_eq!(GainSweep::from(GainType::Lna(vec![1, 2])).points(), vec![GainIndex::new(0, 1, 0), GainIndex::new(0, 2, 0)]);
+        assert_eq!(GainIndex::new(1, 2, 3).file_name("HB"), "HB_iq_1_2_03.txt");
+    }
+
+    #[test]
+    fn test_gain_word() {
+        assert_eq!(GainIndex::new(0, 0, 0).to_word(), 0x400);
+        assert_eq!(GainIndex::new(1, 0, 1).to_word(), 0x602);
+        assert_eq!(GainIndex::MAX.to_word(), 0x7fe);
+        assert_eq!(GainIndex::from_word(GainIndex::new(1, 5, 17).to_word()), GainIndex::new(1, 5, 17));
+    }
+
+    #[test]
+    fn test_gain_ranges() {
+        assert!(GainSweep::from(GainType::Vga(vec![0, 8, 31])).validate().is_ok());
+        assert!(GainSweep::from(GainType::Vga(vec![0, 32])).validate().is_err());
+        assert!(GainSweep::from(GainType::Lna(vec![8])).validate().is_err());
+        assert!(GainSweep::from(GainType::Fem(vec![2])).validate().is_err());
+        assert!(GainSweep::from(GainType::Vga(Vec::new())).validate().is_err());
+        assert!(GainSweep::cartesian(vec![1], Vec::new(), Vec::new()).with_base(GainIndex::new(0, 9, 0)).validate().is_err());
+        assert!(GainSweep::Points(vec![GainIndex::new(1, 7, 40)]).validate().is_err());
+        assert!(GainType::from_name("Pga", vec![0]).is_err());
+    }
 
+    #[test]
+    fn test_iq_engine_args() {
+        assert_eq!(IqEngineConfig::new(0xe000).to_args(), "0 1 0 15 0 e000 0 2 0 1 0 0 0");
+        assert!(IqEngineConfig::new(0x1c000).validate().is_ok());
+        assert!(IqEngineConfig::new(0x40000).validate().is_err());
+        assert!(IqEngineConfig { path_mask: 0, ..IqEngineConfig::new(0xe000) }.validate().is_err());
+        assert!(IqEngineConfig { loop_capture: 2, ..IqEngineConfig::new(0xe000) }.validate().is_err());
+    }
+
+    #[test]
+    fn test_rx_config_validate() {
+        let hb = BandPlan::builtin(Band::HB).unwrap();
+        let lb = BandPlan::builtin(Band::LB).unwrap();
+        let vht80 = RxConfig { center: Some(5210), bandwidth: Bandwidth::Bw80, ..RxConfig::new(5180) };
+        assert!(vht80.validate(Band::HB, &hb).is_ok());
+        assert!(RxConfig { center: Some(5200), ..vht80.clone() }.validate(Band::HB, &hb).is_err());
+        assert!(RxConfig { bandwidth: Bandwidth::Bw20, ..vht80.clone() }.validate(Band::HB, &hb).is_err());
+        assert!(vht80.validate(Band::LB, &lb).is_err());
+        assert!(RxConfig::default_for(&lb).validate(Band::LB, &lb).is_ok());
+        assert!(Bandwidth::from_mhz(60).is_err());
+    }
+
+    #[test]
+    fn test_band_names() {
+        assert_eq!("HB".parse::<Band>().unwrap(), Band::HB);
+        let six: Band = "6G".parse().unwrap();
+        assert_eq!(six, "6G".parse().unwrap());
+        assert_eq!(six.to_string(), "6G");
+        assert_eq!(BandPlan::builtin(six).unwrap().default_freq, 5955);
+        assert!("6G_ch1".parse::<Band>().is_err());
+        assert!("".parse::<Band>().is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::ops::{Deref, DerefMut};
+use crate::client::Dut;
+use crate::config::{Band, RxConfig};
+
+/// Runs with every band but one shut down. The other bands are brought back
+/// up when the guard is dropped, also when the sweep errors out or panics.
+pub struct BandGuard<'a> {
+    dut: &'a mut Dut,
+    shut_down: Vec<Band>,
+}
+
+impl<'a> BandGuard<'a> {
+    pub fn new(dut: &'a mut Dut, band: Band) -> anyhow::Result<Self> {
+        let mut guard = Self { dut, shut_down: Vec::new() };
+        let BandGuard { dut, shut_down } = &mut guard;
+        dut.isolate_band(band, shut_down)?;
+        Ok(guard)
+    }
+}
+
+impl Deref for BandGuard<'_> {
+    type Target = Dut;
+
+    fn deref(&self) -> &Dut {
+        self.dut
+    }
+}
+
+impl DerefMut for BandGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Dut {
+        self.dut
+    }
+}
+
+impl Drop for BandGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.dut.restore_bands(&mut self.shut_down);
+    }
+}
+
+/// Keeps RX of a band open until dropped.
+pub struct RxGuard<'a> {
+    dut: &'a mut Dut,
+    band: Band,
+}
+
+impl<'a> RxGuard<'a> {
+    pub fn new(dut: &'a mut Dut, band: Band, config: &RxConfig) -> anyhow::Result<Self> {
+        dut.open_rx_with(band, config)?;
+        Ok(Self { dut, band })
+    }
+}
+
+impl Deref for RxGuard<'_> {
+    type Target = Dut;
+
+    fn deref(&self) -> &Dut {
+        self.dut
+    }
+}
+
+impl DerefMut for RxGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Dut {
+        self.dut
+    }
+}
+
+impl Drop for RxGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.dut.close_rx(self.band) {
+            log::error!("Could not close RX of {}: {:#}", self.band, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Band, BandState, RxConfig};
+    use crate::guard::{BandGuard, RxGuard};
+    use crate::mock::mock_dut;
+
+    #[test]
+    fn test_guards_restore_on_error() {
+        let (board, mut dut, _dump_dir) = mock_dut("guards");
+
+        let res: anyhow::Result<()> = (|| {
+            let mut lb = BandGuard::new(&mut dut, Band::LB)?;
+            assert_eq!(lb.band_state(Band::HB), BandState::Unbound);
+            let mut rx = RxGuard::new(&mut lb, Band::LB, &RxConfig::new(2437))?;
+            rx.fix_gain(Band::LB, 0, 1, 2)?;
+            Err(anyhow::anyhow!("sweep failed"))
+        })();
+
+        assert!(res.is_err());
+        assert_eq!(dut.band_state(Band::HB), BandState::Up);
+        assert_eq!(dut.band_state(Band::LB), BandState::Up);
+        let board = board.lock().unwrap();
+        assert_eq!(board.ate_log, vec![
+            "ate_cmd wlan1 fastconfig -f 2437 -c 2437 -w 1 -u 1 -r", "ate_cmd wlan1 fastconfig -k", "ifconfig wlan0 up",
+        ]);
+    }
+}
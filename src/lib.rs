@@ -2,13 +2,27 @@ use pyo3::{pyfunction, wrap_pyfunction, Bound, PyResult};
 use pyo3::types::PyModule;
 use pyo3::prelude::*;
 use walkdir::WalkDir;
-use crate::client::PyDut;
-use crate::rfmetrics::FileParser;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::client::{python_signals, BandContext, PyDut, RxContext};
+use crate::config::IqEngineConfig;
+use crate::plan::TestPlan;
+use crate::testcase::PointResult;
+use crate::rfmetrics::{check_spread_threshold, FileParser, DEFAULT_SPREAD_THRESHOLD};
+use crate::snapshot::{RegChange, RegSnapshot};
 
 mod client;
 mod config;
+pub mod guard;
+pub mod mock;
+pub mod plan;
+pub mod profile;
+mod regmap;
 mod rfmetrics;
+pub mod sim;
+mod snapshot;
 mod testcase;
+pub mod transport;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
@@ -21,8 +35,10 @@ fn init_logger() -> PyResult<()> {
 }
 
 #[pyfunction]
-fn parse_dir(dir: String) -> PyResult<()> {
-    let mut file_list = FileParser::new(Vec::new());
+#[pyo3(signature = (dir, spread_threshold=DEFAULT_SPREAD_THRESHOLD))]
+fn parse_dir(dir: String, spread_threshold: f64) -> PyResult<()> {
+    check_spread_threshold(spread_threshold).map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{:#}", e)))?;
+    let mut file_list = FileParser::new(Vec::new()).with_spread_threshold(spread_threshold);
     for entry in WalkDir::new(dir)
         .into_iter()
         .filter_map(|e| e.ok())
@@ -30,10 +46,38 @@ fn parse_dir(dir: String) -> PyResult<()> {
         .filter(|e| e.path().extension().map(|ext| ext == "txt").unwrap_or(false)) {
         file_list.add_file(entry.path().display().to_string());
     }
-    file_list.sort_file()
-        .parse_and_write().unwrap();
-    Ok(())
+    file_list.with_bands_from_files()
+        .sort_file()
+        .parse_and_write(Path::new("iq_dump"))
+        .map_err(|e| pyo3::exceptions::PyOSError::new_err(format!("{:#}", e)))
+
+}
+
+/// Registers that differ between two snapshot files.
+#[pyfunction]
+fn diff_snapshots(before: String, after: String) -> PyResult<Vec<RegChange>> {
+    let load = |path: &str| RegSnapshot::load(path)
+        .map_err(|e| pyo3::exceptions::PyValueError::new_err(format!("{:#}", e)));
+    Ok(load(&before)?.diff(&load(&after)?))
+}
 
+/// Run a JSON test plan, on `addr` instead of the plan's board if given.
+/// Returns a `PointResult` per capture.
+#[pyfunction]
+#[pyo3(signature = (path, addr=None))]
+fn run_plan(path: String, addr: Option<String>) -> PyResult<Vec<PointResult>> {
+    let value_error = |e: anyhow::Error| pyo3::exceptions::PyValueError::new_err(format!("{:#}", e));
+    let runtime_error = |e: anyhow::Error| pyo3::exceptions::PyRuntimeError::new_err(format!("{:#}", e));
+    let plan = TestPlan::load(&path).map_err(value_error)?;
+    let mut dut = plan.connect(addr.as_deref())
+        .map_err(|e| pyo3::exceptions::PyConnectionError::new_err(format!("{:#}", e)))?;
+    let interrupted = Arc::new(Mutex::new(None));
+    dut.set_interrupt_check(python_signals(interrupted.clone()));
+    let res = plan.run(&mut dut);
+    if let Some(e) = interrupted.lock().unwrap().take() {
+        return Err(e);
+    }
+    res.map(|report| report.points).map_err(runtime_error)
 }
 
 #[cfg(test)]
@@ -51,7 +95,13 @@ mod tests {
 fn iq_dump(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(init_logger, m)?)?;
     m.add_function(wrap_pyfunction!(parse_dir, m)?)?;
+    m.add_function(wrap_pyfunction!(diff_snapshots, m)?)?;
+    m.add_function(wrap_pyfunction!(run_plan, m)?)?;
     m.add_class::<PyDut>()?;
+    m.add_class::<BandContext>()?;
+    m.add_class::<RxContext>()?;
+    m.add_class::<IqEngineConfig>()?;
+    m.add_class::<PointResult>()?;
     Ok(())
 }
 
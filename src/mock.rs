@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::f64::consts::PI;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use anyhow::Context;
+use strum::VariantNames;
+use flate2::write::GzEncoder;
+use crate::client::{Capabilities, CmdOutput, Compression, DumpCommand, ResponseHeader};
+
+/// Produces the body of the IQ text file the board writes on `DumpIQ`.
+pub trait CaptureSource: Send {
+    fn capture(&mut self, band: &str, regs: &HashMap<u32, u32>) -> Vec<u8>;
+}
+
+/// Fixed single tone on both paths, independent of the register state.
+pub struct ToneSource {
+    pub tone_mhz: f64,
+    pub fs_mhz: f64,
+    pub amplitude: f64,
+    pub samples: usize,
+}
+
+impl Default for ToneSource {
+    fn default() -> Self {
+        Self {
+            tone_mhz: 2.5,
+            fs_mhz: 40.0,
+            amplitude: 1000.0,
+            samples: 4096,
+        }
+    }
+}
+
+impl CaptureSource for ToneSource {
+    fn capture(&mut self, _band: &str, _regs: &HashMap<u32, u32>) -> Vec<u8> {
+        let step = 2.0 * PI * self.tone_mhz / self.fs_mhz;
+        let path = |phase: f64| -> Vec<(i16, i16)> {
+            (0..self.samples)
+                .map(|n| {
+                    let arg = step * n as f64 + phase;
+                    ((self.amplitude * arg.cos()) as i16, (self.amplitude * arg.sin()) as i16)
+                })
+                .collect()
+        };
+        write_iq_text(&path(0.0), &path(PI / 4.0))
+    }
+}
+
+/// Render two paths in the board's text format: one `0x00QQQIII` line per
+/// sample, path1 and path2 interleaved.
+pub fn write_iq_text(path1: &[(i16, i16)], path2: &[(i16, i16)]) -> Vec<u8> {
+    let mut text = String::with_capacity((path1.len() + path2.len()) * 11);
+    for (p1, p2) in path1.iter().zip(path2.iter()) {
+        for (i, q) in [p1, p2] {
+            text.push_str(&format!("0x00{:03x}{:03x}\n", *q as u16 & 0x0fff, *i as u16 & 0x0fff));
+        }
+    }
+    text.into_bytes()
+}
+
+/// State of the simulated board, shared by every connection of a `MockServer`.
+pub struct MockBoard {
+    pub regs: HashMap<u32, u32>,
+    /// Registers that silently ignore `SetReg`.
+    pub read_only: HashSet<u32>,
+    /// Registers whose `SetReg` is answered with an error.
+    pub rejected: HashSet<u32>,
+    pub files: BTreeMap<String, Vec<u8>>,
+    pub shell_log: Vec<String>,
+    pub ate_log: Vec<String>,
+    /// Canned results of shell/ATE command lines; anything else succeeds silently.
+    pub cmd_outputs: HashMap<String, CmdOutput>,
+    pub ate_initialized: bool,
+    /// Wiphys registered by the driver, by index, with the device they belong to.
+    /// `echo <dev> > .../bind` registers a new phy, as the kernel does.
+    pub phys: BTreeMap<u8, String>,
+    next_phy: u8,
+    /// Answer to `Hello`; `None` rejects it like a server that predates the handshake.
+    pub capabilities: Option<Capabilities>,
+    /// Close the connection instead of answering the n-th next command.
+    pub drop_after: Option<usize>,
+    /// Flip a byte in the body of the n-th next `CopyFiles` answer.
+    pub corrupt_after: Option<usize>,
+    /// Send only half of the n-th next answer, then close the connection.
+    pub truncate_after: Option<usize>,
+    /// Swallow the n-th next command without answering, as a hung board would.
+    pub stall_after: Option<usize>,
+    source: Box<dyn CaptureSource>,
+}
+
+impl MockBoard {
+    pub fn new(source: Box<dyn CaptureSource>) -> Self {
+        Self {
+            regs: HashMap::new(),
+            read_only: HashSet::new(),
+            rejected: HashSet::new(),
+            files: BTreeMap::new(),
+            shell_log: Vec::new(),
+            ate_log: Vec::new(),
+            cmd_outputs: HashMap::new(),
+            ate_initialized: false,
+            phys: BTreeMap::from([(0, "20000000.wmac".into()), (1, "30000000.wmac".into())]),
+            next_phy: 2,
+            capabilities: Some(Capabilities {
+                server_version: format!("mock-{}", env!("CARGO_PKG_VERSION")),
+                chip_model: "mock".into(),
+                commands: DumpCommand::VARIANTS.iter().chain(&["DumpIQBand"]).map(|s| s.to_string()).collect(),
+                max_transfer_size: 16 * 1024 * 1024,
+            }),
+            drop_after: None,
+            corrupt_after: None,
+            truncate_after: None,
+            stall_after: None,
+            source,
+        }
+    }
+
+    fn handle(&mut self, cmd: DumpCommand) -> (ResponseHeader, Vec<u8>) {
+        log::debug!("mock recv {:?}", cmd);
+        match cmd {
+            DumpCommand::DumpIQ { band_5g, band, file_name } => {
+                let band = match band.as_str() {
+                    "" if band_5g => "HB",
+                    "" => "LB",
+                    band => band,
+                };
+                let body = self.source.capture(band, &self.regs);
+                self.files.insert(file_name, body);
+                ok(Vec::new())
+            }
+            DumpCommand::DelFiles => {
+                self.files.clear();
+                ok(Vec::new())
+            }
+            DumpCommand::CopyFiles(file_name) => match self.files.get(&file_name) {
+                Some(body) => {
+                    let (mut header, mut body) = ok(body.clone());
+                    header.crc32 = Some(crc32fast::hash(&body));
+                    if countdown(&mut self.corrupt_after) && !body.is_empty() {
+                        log::warn!("mock corrupting {}", file_name);
+                        body[0] ^= 0xff;
+                    }
+                    (header, body)
+                }
+                None => error(),
+            },
+            DumpCommand::CopyFilesEx { file_name, offset, compression } => match self.files.get(&file_name) {
+                Some(body) if offset <= body.len() as u64 => {
+                    let tail = &body[offset as usize..];
+                    let body = match compression {
+                        Compression::None => tail.to_vec(),
+                        Compression::Gzip => {
+                            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                            encoder.write_all(tail).and_then(|_| encoder.finish()).unwrap()
+                        }
+                        Compression::Zstd => zstd::encode_all(tail, 0).unwrap(),
+                    };
+                    let (mut header, body) = ok(body);
+                    header.crc32 = Some(crc32fast::hash(&body));
+                    header.compression = Some(compression);
+                    header.offset = offset;
+                    (header, body)
+                }
+                _ => error(),
+            },
+            DumpCommand::SetReg { addr, value } => {
+                if self.rejected.contains(&addr) {
+                    return error();
+                }
+                if !self.read_only.contains(&addr) {
+                    self.regs.insert(addr, value);
+                }
+                ok(Vec::new())
+            }
+            DumpCommand::GetReg { addr } => {
+                let (mut header, body) = ok(Vec::new());
+                header.value = Some(self.regs.get(&addr).copied().unwrap_or(0));
+                (header, body)
+            }
+            DumpCommand::ShellCmd(cmd) => {
+                let out = self.shell(&cmd);
+                self.shell_log.push(cmd);
+                out
+            }
+            DumpCommand::ATEInit => {
+                self.ate_initialized = true;
+                ok(Vec::new())
+            }
+            DumpCommand::ATECmd { cmd, args } => {
+                let line = format!("{} {}", cmd, args.join(" "));
+                let out = self.cmd_output(&line);
+                self.ate_log.push(line);
+                out
+            }
+            DumpCommand::Hello { .. } => match &self.capabilities {
+                Some(caps) => ok(serde_json::to_vec(caps).unwrap()),
+                None => error(),
+            },
+        }
+    }
+
+    /// Emulate the sysfs side of binding/unbinding wmac devices.
+    fn shell(&mut self, cmd: &str) -> (ResponseHeader, Vec<u8>) {
+        if self.cmd_outputs.contains_key(cmd) {
+            return self.cmd_output(cmd);
+        }
+        if cmd.starts_with("ls -l /sys/class/ieee80211") {
+            let stdout = self.phys.iter()
+                .map(|(idx, dev)| format!(
+                    "lrwxrwxrwx    1 root     root             0 Jan  1 00:00 phy{idx} -> ../../devices/platform/{dev}/ieee80211/phy{idx}\n"))
+                .collect();
+            return ok(serde_json::to_vec(&CmdOutput { exit_code: Some(0), stdout, ..CmdOutput::default() }).unwrap());
+        }
+        if let Some((dev, sysfs)) = cmd.strip_prefix("echo ").and_then(|rest| rest.split_once(" > ")) {
+            if sysfs.ends_with("/unbind") {
+                self.phys.retain(|_, bound| bound != dev);
+            } else if sysfs.ends_with("/bind") && !self.phys.values().any(|bound| bound == dev) {
+                self.phys.insert(self.next_phy, dev.to_string());
+                self.next_phy += 1;
+            }
+        }
+        self.cmd_output(cmd)
+    }
+
+    fn cmd_output(&self, line: &str) -> (ResponseHeader, Vec<u8>) {
+        let out = self.cmd_outputs.get(line).cloned().unwrap_or_else(|| CmdOutput {
+            exit_code: Some(0),
+            ..CmdOutput::default()
+        });
+        ok(serde_json::to_vec(&out).unwrap())
+    }
+}
+
+impl Default for MockBoard {
+    fn default() -> Self {
+        Self::new(Box::new(ToneSource::default()))
+    }
+}
+
+fn ok(body: Vec<u8>) -> (ResponseHeader, Vec<u8>) {
+    (ResponseHeader { is_error: false, file_size: body.len() as u64, ..Default::default() }, body)
+}
+
+fn error() -> (ResponseHeader, Vec<u8>) {
+    (ResponseHeader { is_error: true, ..Default::default() }, Vec::new())
+}
+
+/// One-shot fault trigger: true when the counter runs out, which also disarms it.
+fn countdown(counter: &mut Option<usize>) -> bool {
+    match counter {
+        Some(0) | Some(1) => {
+            *counter = None;
+            true
+        }
+        Some(n) => {
+            *n -= 1;
+            false
+        }
+        None => false,
+    }
+}
+
+/// Serve one client over any byte stream until it hangs up.
+pub fn serve<S: Read + Write>(board: &Mutex<MockBoard>, stream: S) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (header, body) = match serde_json::from_str::<DumpCommand>(&line) {
+            Ok(cmd) => {
+                let mut board = board.lock().unwrap();
+                if countdown(&mut board.drop_after) {
+                    log::warn!("mock dropping connection on {:?}", cmd);
+                    return Ok(());
+                }
+                if countdown(&mut board.stall_after) {
+                    log::warn!("mock stalling on {:?}", cmd);
+                    continue;
+                }
+                let truncate = countdown(&mut board.truncate_after);
+                let (header, body) = board.handle(cmd);
+                if truncate {
+                    log::warn!("mock truncating answer of {} bytes", body.len());
+                    let mut resp = serde_json::to_vec(&header)?;
+                    resp.push(b'\n');
+                    resp.extend_from_slice(&body[..body.len() / 2]);
+                    reader.get_mut().write_all(&resp)?;
+                    return Ok(());
+                }
+                (header, body)
+            }
+            Err(e) => {
+                log::warn!("mock could not parse command {}: {}", line.trim(), e);
+                error()
+            }
+        };
+        let mut resp = serde_json::to_vec(&header)?;
+        resp.push(b'\n');
+        resp.extend_from_slice(&body);
+        let stream = reader.get_mut();
+        stream.write_all(&resp)?;
+        stream.flush()?;
+    }
+}
+
+/// TCP stand-in for dumpiq_server, one thread per connection.
+pub struct MockServer {
+    listener: TcpListener,
+    board: Arc<Mutex<MockBoard>>,
+}
+
+impl MockServer {
+    pub fn bind(addr: &str) -> anyhow::Result<Self> {
+        Self::with_board(addr, MockBoard::default())
+    }
+
+    pub fn with_board(addr: &str, board: MockBoard) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Could not bind mock server on {}", addr))?;
+        Ok(Self {
+            listener,
+            board: Arc::new(Mutex::new(board)),
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn board(&self) -> Arc<Mutex<MockBoard>> {
+        self.board.clone()
+    }
+
+    pub fn run(&self) -> anyhow::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let board = self.board.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                log::info!("mock client connected {:?}", peer);
+                if let Err(e) = serve(&board, &stream) {
+                    log::warn!("mock client {:?} dropped: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Run the accept loop on a background thread and return the bound address.
+    pub fn spawn(self) -> anyhow::Result<SocketAddr> {
+        let addr = self.local_addr()?;
+        thread::spawn(move || self.run());
+        Ok(addr)
+    }
+}
+
+/// Temporary directory that is removed on drop, so a failing test does not leave it behind.
+#[cfg(test)]
+pub(crate) struct TempDirGuard(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDirGuard {
+    pub(crate) fn new(tag: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("iq_dump_{}_{}", tag, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDirGuard {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDirGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A `Dut` connected to a fresh mock board, dumping into its own temp dir.
+#[cfg(test)]
+pub(crate) fn mock_dut(tag: &str) -> (Arc<Mutex<MockBoard>>, crate::client::Dut, TempDirGuard) {
+    mock_dut_with(tag, MockBoard::default())
+}
+
+/// `mock_dut` on a board prepared before the connection is made.
+#[cfg(test)]
+pub(crate) fn mock_dut_with(tag: &str, board: MockBoard) -> (Arc<Mutex<MockBoard>>, crate::client::Dut, TempDirGuard) {
+    let server = MockServer::with_board("127.0.0.1:0", board).unwrap();
+    let board = server.board();
+    let addr = server.spawn().unwrap();
+    let dir = TempDirGuard::new(tag);
+    let mut dut = crate::client::Dut::connect(&addr.to_string()).unwrap();
+    dut.set_dump_dir(&*dir);
+    (board, dut, dir)
+}
+
+/// A board whose dumps break off in the middle of a sample line.
+#[cfg(test)]
+pub(crate) struct GarbledSource;
+
+#[cfg(test)]
+impl CaptureSource for GarbledSource {
+    fn capture(&mut self, _band: &str, _regs: &HashMap<u32, u32>) -> Vec<u8> {
+        b"0x00abc123\n0x00abc\n".to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Band, GainType, TestBand};
+    use crate::mock::mock_dut;
+    use crate::testcase::TestCase;
+
+    #[test]
+    fn test_run_test_against_mock() {
+        let (board, mut dut, dump_dir) = mock_dut("mock");
+        dut.ate_init().unwrap();
+        TestBand::new(Band::HB, GainType::Vga((0..3).collect())).run_test(&mut dut);
+
+        assert_eq!(dut.file_list.file_list.len(), 3);
+        assert!(dump_dir.join("HB_iq_0_0_02.txt").exists());
+        let board = board.lock().unwrap();
+        assert!(board.ate_initialized);
+        assert!(board.files.is_empty());
+        assert_eq!(board.regs.get(&0x30c02f88), Some(&0x34041404));
+    }
+}
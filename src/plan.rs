@@ -0,0 +1,489 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use crate::client::{Dut, DutOptions};
+use crate::config::{Band, Bandwidth, GainIndex, GainSweep, RxConfig, TestBand};
+use crate::guard::{BandGuard, RxGuard};
+use crate::profile::{ChipProfile, GenericProfile, SiwifiProfile};
+use crate::regmap::RegisterMap;
+use crate::rfmetrics::{check_spread_threshold, FileParser, DEFAULT_SPREAD_THRESHOLD};
+use crate::testcase::{OnChannel, SweepReport, TestCase};
+
+/// A test session read from a JSON file, see `plans/example.json`. Paths in
+/// the plan are relative to the plan file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TestPlan {
+    /// Board address, as for `PyDut`.
+    pub dut: String,
+    /// Chip profile file; siwifi when missing.
+    #[serde(default)]
+    pub profile: Option<PathBuf>,
+    #[serde(default)]
+    pub regmap: Option<PathBuf>,
+    #[serde(default)]
+    pub options: PlanOptions,
+    #[serde(default = "yes")]
+    pub ate_init: bool,
+    #[serde(default = "default_dump_dir")]
+    pub dump_dir: PathBuf,
+    pub steps: Vec<PlanStep>,
+    #[serde(default)]
+    pub report: PlanReport,
+}
+
+/// `DutOptions` to change; the rest keep their defaults. Times are in seconds.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PlanOptions {
+    pub read_timeout: Option<f64>,
+    pub write_timeout: Option<f64>,
+    pub heartbeat_interval: Option<f64>,
+    pub max_retries: Option<u32>,
+    pub retry_delay: Option<f64>,
+    pub compression: Option<String>,
+    pub verify_writes: Option<bool>,
+    pub restore_registers: Option<bool>,
+    pub auto_transitions: Option<bool>,
+}
+
+/// Sweeps on one band.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PlanStep {
+    pub band: String,
+    /// Shut the other bands down for the step and bring them back after.
+    #[serde(default = "yes")]
+    pub isolate: bool,
+    /// Channels to run every sweep on; the band's default channel when empty.
+    #[serde(default)]
+    pub channels: Vec<PlanChannel>,
+    pub sweeps: Vec<PlanSweep>,
+    /// Captures per gain point.
+    #[serde(default = "one")]
+    pub repeats: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PlanChannel {
+    pub freq: u32,
+    #[serde(default)]
+    pub center: Option<u32>,
+    #[serde(default = "default_bw")]
+    pub bw: u32,
+    #[serde(default)]
+    pub extra: Vec<String>,
+}
+
+/// Stage lists swept as a cartesian product around `base`, or explicit
+/// `[fem, lna, vga]` points.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PlanSweep {
+    #[serde(default)]
+    pub fem: Vec<u8>,
+    #[serde(default)]
+    pub lna: Vec<u8>,
+    #[serde(default)]
+    pub vga: Vec<u8>,
+    #[serde(default)]
+    pub base: [u8; 3],
+    #[serde(default)]
+    pub points: Option<Vec<[u8; 3]>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PlanReport {
+    /// Write the Excel workbook after the last step.
+    #[serde(default = "yes")]
+    pub workbook: bool,
+    /// Workbook file; `result.xlsx` in the dump dir when missing.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Flag repeated points whose power spreads more than this many dB.
+    #[serde(default = "default_spread_threshold")]
+    pub spread_threshold: f64,
+}
+
+impl Default for PlanReport {
+    fn default() -> Self {
+        Self { workbook: true, path: None, spread_threshold: DEFAULT_SPREAD_THRESHOLD }
+    }
+}
+
+fn default_spread_threshold() -> f64 {
+    DEFAULT_SPREAD_THRESHOLD
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn default_bw() -> u32 {
+    20
+}
+
+fn default_dump_dir() -> PathBuf {
+    PathBuf::from("iq_dump")
+}
+
+/// A step with every name and number checked against the chip profile.
+struct Step {
+    band: Band,
+    isolate: bool,
+    channels: Vec<RxConfig>,
+    /// Channels were listed in the plan, so they go into the file names.
+    named_channels: bool,
+    sweeps: Vec<TestBand>,
+}
+
+impl TestPlan {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read test plan {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        Self::from_json(&text, base).with_context(|| format!("Invalid test plan {}", path.display()))
+    }
+
+    /// Parse a plan whose relative paths are relative to `base`.
+    pub fn from_json(text: &str, base: &Path) -> anyhow::Result<Self> {
+        let mut plan: TestPlan = serde_json::from_str(text)?;
+        for path in [plan.profile.as_mut(), plan.regmap.as_mut(), Some(&mut plan.dump_dir), plan.report.path.as_mut()]
+            .into_iter()
+            .flatten() {
+            *path = base.join(&*path);
+        }
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    fn profile(&self) -> anyhow::Result<Box<dyn ChipProfile>> {
+        Ok(match &self.profile {
+            Some(path) => Box::new(GenericProfile::load(path)?),
+            None => Box::new(SiwifiProfile),
+        })
+    }
+
+    fn dut_options(&self) -> anyhow::Result<DutOptions> {
+        let o = &self.options;
+        let default = DutOptions::default();
+        let secs = |secs: Option<f64>, default: Option<Duration>| match secs {
+            Some(secs) => Duration::try_from_secs_f64(secs)
+                .map(Some)
+                .map_err(|_| anyhow!("Time {} s in options must be a non-negative number of seconds", secs)),
+            None => Ok(default),
+        };
+        Ok(DutOptions {
+            read_timeout: secs(o.read_timeout, default.read_timeout)?,
+            write_timeout: secs(o.write_timeout, default.write_timeout)?,
+            heartbeat_interval: secs(o.heartbeat_interval, default.heartbeat_interval)?,
+            max_retries: o.max_retries.unwrap_or(default.max_retries),
+            retry_delay: secs(o.retry_delay, Some(default.retry_delay))?.unwrap_or_default(),
+            compression: match &o.compression {
+                Some(name) => name.parse().map_err(|_| anyhow!("Unknown compression {}", name))?,
+                None => default.compression,
+            },
+            verify_writes: o.verify_writes.unwrap_or(default.verify_writes),
+            restore_registers: o.restore_registers.unwrap_or(default.restore_registers),
+            auto_transitions: o.auto_transitions.unwrap_or(default.auto_transitions),
+        })
+    }
+
+    /// Check the whole plan against the chip profile, before touching a board.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.dut_options()?;
+        check_spread_threshold(self.report.spread_threshold)?;
+        if let Some(regmap) = &self.regmap {
+            RegisterMap::load(regmap)?;
+        }
+        self.steps(self.profile()?.as_ref())?;
+        Ok(())
+    }
+
+    fn steps(&self, profile: &dyn ChipProfile) -> anyhow::Result<Vec<Step>> {
+        if self.steps.is_empty() {
+            return Err(anyhow!("Test plan has no steps"));
+        }
+        self.steps.iter().enumerate()
+            .map(|(idx, step)| Self::step(step, profile).with_context(|| format!("Step {}", idx + 1)))
+            .collect()
+    }
+
+    fn step(step: &PlanStep, profile: &dyn ChipProfile) -> anyhow::Result<Step> {
+        let band: Band = step.band.parse()?;
+        if !profile.bands().contains(&band) {
+            return Err(anyhow!("Profile {} has no band {}", profile.name(), band));
+        }
+        let plan = profile.band_plan(band)
+            .ok_or_else(|| anyhow!("Profile {} has no channel plan for {}", profile.name(), band))?;
+        let channels = if step.channels.is_empty() {
+            vec![RxConfig::default_for(&plan)]
+        } else {
+            step.channels.iter()
+                .map(|ch| {
+                    let config = RxConfig {
+                        freq: ch.freq,
+                        center: ch.center,
+                        bandwidth: Bandwidth::from_mhz(ch.bw)?,
+                        extra_args: ch.extra.clone(),
+                    };
+                    config.validate(band, &plan)?;
+                    Ok(config)
+                })
+                .collect::<anyhow::Result<_>>()?
+        };
+        if step.repeats == 0 {
+            return Err(anyhow!("repeats must be at least 1"));
+        }
+        if step.sweeps.is_empty() {
+            return Err(anyhow!("No sweeps on {}", band));
+        }
+        let sweeps = step.sweeps.iter()
+            .map(|sweep| Ok(TestBand::new(band, sweep.gain_sweep()?).with_repeats(step.repeats)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Step { band, isolate: step.isolate, channels, named_channels: !step.channels.is_empty(), sweeps })
+    }
+
+    /// Connect to the plan's board, or to `addr` instead.
+    pub fn connect(&self, addr: Option<&str>) -> anyhow::Result<Dut> {
+        Dut::connect(addr.unwrap_or(&self.dut))
+    }
+
+    fn setup(&self, dut: &mut Dut) -> anyhow::Result<()> {
+        dut.set_options(self.dut_options()?)?;
+        dut.set_profile(self.profile()?);
+        if let Some(regmap) = &self.regmap {
+            dut.load_regmap(&regmap.display().to_string())?;
+        }
+        dut.set_dump_dir(&self.dump_dir);
+        Ok(())
+    }
+
+    /// Run every step on `dut`, then write the report. A gain point, channel
+    /// or band that fails is logged, skipped and listed in the returned report;
+    /// after a Ctrl-C the remaining captures are listed as skipped. The
+    /// workbook is written from whatever was captured.
+    pub fn run(&self, dut: &mut Dut) -> anyhow::Result<SweepReport> {
+        let steps = self.steps(self.profile()?.as_ref())?;
+        self.setup(dut)?;
+        if self.ate_init {
+            dut.resilient(|dut| dut.ate_init())?;
+        }
+        let mut report = SweepReport::default();
+        let mut stopped = None;
+        for step in &steps {
+            if stopped.is_none() && let Err(e) = dut.check_interrupt() {
+                log::error!("Plan stopped: {}", e);
+                stopped = Some(format!("{:#}", e));
+            }
+            if let Some(e) = &stopped {
+                report.extend(Self::skipped(step, &step.channels, e));
+                continue;
+            }
+            log::info!("Plan step on {}: {} channel(s), {} sweep(s)", step.band, step.channels.len(), step.sweeps.len());
+            let res = if step.isolate {
+                match BandGuard::new(dut, step.band) {
+                    Ok(mut guard) => Self::run_step(&mut guard, step, &mut report),
+                    Err(e) => {
+                        log::error!("Skip step on {}: {}", step.band, e);
+                        report.extend(Self::skipped(step, &step.channels, &format!("{:#}", e)));
+                        Ok(())
+                    }
+                }
+            } else {
+                Self::run_step(dut, step, &mut report)
+            };
+            if let Err(e) = res {
+                log::error!("Plan stopped: {}", e);
+                stopped = Some(format!("{:#}", e));
+            }
+        }
+        if self.report.workbook {
+            let path = self.report.path.clone().unwrap_or_else(|| self.dump_dir.join("result.xlsx"));
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            FileParser::new(dut.file_list.file_list.clone())
+                .with_metrics(dut.file_list.metrics.clone())
+                .with_bands(dut.bands())
+                .with_spread_threshold(self.report.spread_threshold)
+                .sort_file()
+                .write_workbook(&path)?;
+            log::info!("Report written to {}", path.display());
+        }
+        Ok(report)
+    }
+
+    /// Run one step into `report`. A channel that can not be opened is skipped;
+    /// an `Err` is an interrupt, with the captures left listed as skipped.
+    fn run_step(dut: &mut Dut, step: &Step, report: &mut SweepReport) -> anyhow::Result<()> {
+        for (idx, config) in step.channels.iter().enumerate() {
+            if let Err(e) = dut.check_interrupt() {
+                report.extend(Self::skipped(step, &step.channels[idx..], &format!("{:#}", e)));
+                return Err(e);
+            }
+            let mut rx = match RxGuard::new(dut, step.band, config) {
+                Ok(rx) => rx,
+                Err(e) => {
+                    log::error!("Skip channel {} MHz: {}", config.freq, e);
+                    report.extend(Self::skipped(step, std::slice::from_ref(config), &format!("{:#}", e)));
+                    continue;
+                }
+            };
+            for sweep in &step.sweeps {
+                if step.named_channels {
+                    report.extend(OnChannel { gain: sweep, freq: config.freq }.run_test(&mut rx));
+                } else {
+                    report.extend(sweep.run_test(&mut rx));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every capture of `step` on `channels`, skipped because of `error`.
+    fn skipped(step: &Step, channels: &[RxConfig], error: &str) -> SweepReport {
+        let mut report = SweepReport::default();
+        for config in channels {
+            for sweep in &step.sweeps {
+                if step.named_channels {
+                    report.extend(OnChannel { gain: sweep, freq: config.freq }.skipped(error));
+                } else {
+                    report.extend(sweep.skipped(error));
+                }
+            }
+        }
+        report
+    }
+}
+
+impl PlanSweep {
+    fn gain_sweep(&self) -> anyhow::Result<GainSweep> {
+        let lists = !(self.fem.is_empty() && self.lna.is_empty() && self.vga.is_empty());
+        let sweep = match &self.points {
+            Some(_) if lists => return Err(anyhow!("A sweep has either stage lists or points, not both")),
+            Some(points) => GainSweep::Points(points.iter()
+                .map(|&[fem, lna, vga]| GainIndex::new(fem, lna, vga))
+                .collect()),
+            None => {
+                let [fem, lna, vga] = self.base;
+                GainSweep::cartesian(self.fem.clone(), self.lna.clone(), self.vga.clone())
+                    .with_base(GainIndex::new(fem, lna, vga))
+            }
+        };
+        sweep.validate()?;
+        Ok(sweep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use anyhow::anyhow;
+    use crate::config::Band;
+    use crate::mock::{MockServer, TempDirGuard};
+    use crate::plan::TestPlan;
+    use crate::testcase::PointStatus;
+
+    #[test]
+    fn test_reject_invalid_plans() {
+        let plan = |steps: &str| TestPlan::from_json(&format!(r#"{{"dut": "127.0.0.1:1", "steps": {}}}"#, steps), Path::new("."));
+        TestPlan::load(concat!(env!("CARGO_MANIFEST_DIR"), "/plans/example.json")).unwrap();
+        assert!(plan(r#"[{"band": "HB", "sweeps": [{"vga": [0, 1]}]}]"#).is_ok());
+        assert!(plan("[]").is_err());
+        assert!(plan(r#"[{"band": "6G", "sweeps": [{"vga": [0]}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "LB", "channels": [{"freq": 5180}], "sweeps": [{"vga": [0]}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "HB", "sweeps": [{"vga": [0], "points": [[0, 0, 1]]}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "HB", "sweeps": [{}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "HB", "sweeps": [{"points": []}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "HB", "sweeps": [{"lna": [0, 8]}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "HB", "repeats": 0, "sweeps": [{"vga": [0]}]}]"#).is_err());
+        assert!(plan(r#"[{"band": "HB", "sweep": [{"vga": [0]}]}]"#).is_err());
+        let missing_regmap = r#"{"dut": "127.0.0.1:1", "regmap": "no_such_regmap.json", "steps": [{"band": "HB", "sweeps": [{"vga": [0]}]}]}"#;
+        assert!(TestPlan::from_json(missing_regmap, Path::new(".")).is_err());
+        for timeout in ["-1", "1e300"] {
+            let options = format!(r#"{{"dut": "127.0.0.1:1", "options": {{"read_timeout": {}}}, "steps": [{{"band": "HB", "sweeps": [{{"vga": [0]}}]}}]}}"#, timeout);
+            assert!(TestPlan::from_json(&options, Path::new(".")).is_err(), "{}", timeout);
+        }
+    }
+
+    #[test]
+    fn test_plan_keeps_partial_report() {
+        let server = MockServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.spawn().unwrap();
+        let dir = TempDirGuard::new("plan_partial");
+
+        let plan = TestPlan::from_json(&format!(r#"{{
+            "dut": "{}",
+            "steps": [
+                {{ "band": "HB", "isolate": false, "sweeps": [{{ "vga": [0, 1] }}] }},
+                {{ "band": "LB", "isolate": false, "sweeps": [{{ "lna": [1, 2] }}] }},
+                {{ "band": "LB", "isolate": false, "channels": [{{ "freq": 2437 }}], "sweeps": [{{ "vga": [3] }}] }}
+            ]
+        }}"#, addr), &dir).unwrap();
+        let mut dut = plan.connect(None).unwrap();
+        // HB is down, so its RX can not be opened and the first step is skipped
+        dut.shut_down_band(Band::HB).unwrap();
+        // Ctrl-C once the two LB points of the second step are done
+        let checks = AtomicUsize::new(0);
+        dut.set_interrupt_check(move || match checks.fetch_add(1, Ordering::SeqCst) {
+            n if n < 6 => Ok(()),
+            _ => Err(anyhow!("Interrupted")),
+        });
+        let report = plan.run(&mut dut).unwrap();
+
+        let status: Vec<_> = report.points().iter().map(|point| (point.band.to_string(), point.freq, point.status())).collect();
+        assert_eq!(status, vec![
+            ("HB".to_string(), None, PointStatus::Skipped), ("HB".to_string(), None, PointStatus::Skipped),
+            ("LB".to_string(), None, PointStatus::Ok), ("LB".to_string(), None, PointStatus::Ok),
+            ("LB".to_string(), Some(2437), PointStatus::Skipped),
+        ]);
+        assert!(report.points()[0].error.as_ref().unwrap().contains("call shut_up_band first"));
+        assert_eq!(report.points()[4].error.as_deref(), Some("Interrupted"));
+        assert!(dir.join("iq_dump").join("result.xlsx").exists());
+    }
+
+    #[test]
+    fn test_run_plan_against_mock() {
+        let server = MockServer::bind("127.0.0.1:0").unwrap();
+        let board = server.board();
+        let addr = server.spawn().unwrap();
+        let dir = TempDirGuard::new("plan");
+
+        let plan = TestPlan::from_json(&format!(r#"{{
+            "dut": "{}",
+            "steps": [
+                {{ "band": "LB", "sweeps": [{{ "lna": [1, 2] }}] }},
+                {{ "band": "LB", "channels": [{{ "freq": 2437 }}], "sweeps": [{{ "points": [[0, 1, 0]] }}] }},
+                {{ "band": "HB", "channels": [{{ "freq": 5180 }}, {{ "freq": 5500, "center": 5510, "bw": 40 }}],
+                   "repeats": 2, "sweeps": [{{ "points": [[1, 3, 10]] }}] }}
+            ]
+        }}"#, addr), &dir).unwrap();
+        let mut dut = plan.connect(None).unwrap();
+        let report = plan.run(&mut dut).unwrap();
+        assert_eq!(report.points().len(), 7);
+        assert_eq!(report.failures().count(), 0);
+
+        let dump_dir = dir.join("iq_dump");
+        for name in ["LB_iq_0_1_00.txt", "LB_iq_0_2_00.txt", "LB_ch2437_iq_0_1_00.txt", "HB_ch5180_iq_1_3_10_r0.txt", "HB_ch5500_iq_1_3_10_r1.txt"] {
+            assert!(dump_dir.join(name).exists(), "{}", name);
+        }
+        assert_eq!(dut.file_list.file_list.len(), 7);
+        assert!(dump_dir.join("result.xlsx").exists());
+        let board = board.lock().unwrap();
+        assert!(board.ate_initialized);
+        assert!(board.ate_log.contains(&"ate_cmd wlan0 fastconfig -f 5500 -c 5510 -w 2 -u 1 -r".to_string()));
+        // LB was shut down for the HB step and brought back up afterwards
+        assert_eq!(board.shell_log.iter().filter(|cmd| cmd.starts_with("echo 20000000.wmac")).count(), 2);
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use crate::config::{Band, BandPlan, Bandwidth, IqEngineConfig, RxConfig};
+use crate::regmap::{substitute, RegisterMap};
+
+/// A command run through `ATECmd`: the program and its arguments.
+pub type AteCall = (String, Vec<String>);
+
+/// Everything about a Wi-Fi SoC the dump flow needs beyond the DumpCommand
+/// protocol: debugfs/sysfs paths, interface names and `ate_cmd` syntax.
+pub trait ChipProfile: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Bands of the chip; every other method is only called with one of these.
+    fn bands(&self) -> Vec<Band>;
+
+    /// Channels `open_rx` accepts on `band`.
+    fn band_plan(&self, band: Band) -> Option<BandPlan> {
+        BandPlan::builtin(band)
+    }
+
+    /// Device the band's wiphy hangs off, as seen under `/sys/devices/platform`.
+    fn phy_device(&self, band: Band) -> String;
+
+    /// Capture settings `dump_iq` uses unless told otherwise.
+    fn iq_engine_default(&self, band: Band) -> IqEngineConfig;
+
+    /// Shell command arming the IQ capture engine of `phy` for `band`.
+    fn iq_engine_cmd(&self, band: Band, phy: u8, config: &IqEngineConfig) -> String;
+
+    /// Shell command detaching the band's MAC from its driver.
+    fn unbind_cmd(&self, band: Band) -> String;
+
+    fn bind_cmd(&self, band: Band) -> String;
+
+    fn iface_up(&self, band: Band) -> AteCall;
+
+    fn open_rx(&self, band: Band, config: &RxConfig) -> AteCall;
+
+    fn close_rx(&self, band: Band) -> AteCall;
+
+    fn register_map(&self) -> RegisterMap;
+}
+
+/// Siwifi SoCs: HB on `30000000.wmac`/`wlan0`, LB on `20000000.wmac`/`wlan1`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SiwifiProfile;
+
+impl SiwifiProfile {
+    fn wmac(band: Band) -> &'static str {
+        if band == Band::HB { "30000000.wmac" } else { "20000000.wmac" }
+    }
+
+    fn iface(band: Band) -> &'static str {
+        if band == Band::HB { "wlan0" } else { "wlan1" }
+    }
+}
+
+impl ChipProfile for SiwifiProfile {
+    fn name(&self) -> &str {
+        "siwifi"
+    }
+
+    fn bands(&self) -> Vec<Band> {
+        vec![Band::HB, Band::LB]
+    }
+
+    fn phy_device(&self, band: Band) -> String {
+        Self::wmac(band).to_string()
+    }
+
+    fn iq_engine_default(&self, band: Band) -> IqEngineConfig {
+        IqEngineConfig::new(if band == Band::HB { 0xe000 } else { 0x1c000 })
+    }
+
+    fn iq_engine_cmd(&self, _band: Band, phy: u8, config: &IqEngineConfig) -> String {
+        format!("echo {} > /sys/kernel/debug/ieee80211/phy{}/siwifi/iq_engine", config.to_args(), phy)
+    }
+
+    fn unbind_cmd(&self, band: Band) -> String {
+        format!("echo {} > /sys/bus/platform/drivers/siwifi_umac/unbind", Self::wmac(band))
+    }
+
+    fn bind_cmd(&self, band: Band) -> String {
+        format!("echo {} > /sys/bus/platform/drivers/siwifi_umac/bind", Self::wmac(band))
+    }
+
+    fn iface_up(&self, band: Band) -> AteCall {
+        ("ifconfig".to_string(), vec![Self::iface(band).to_string(), "up".to_string()])
+    }
+
+    fn open_rx(&self, band: Band, config: &RxConfig) -> AteCall {
+        let width = match config.bandwidth {
+            Bandwidth::Bw20 => 1,
+            Bandwidth::Bw40 => 2,
+            Bandwidth::Bw80 => 3,
+            Bandwidth::Bw160 => 4,
+        };
+        let args = format!("{} fastconfig -f {} -c {} -w {} -u 1 -r", Self::iface(band), config.freq, config.center(), width);
+        let mut args = split_args(&args);
+        args.extend(config.extra_args.iter().cloned());
+        ("ate_cmd".to_string(), args)
+    }
+
+    fn close_rx(&self, band: Band) -> AteCall {
+        ("ate_cmd".to_string(), split_args(&format!("{} fastconfig -k", Self::iface(band))))
+    }
+
+    fn register_map(&self) -> RegisterMap {
+        RegisterMap::siwifi()
+    }
+}
+
+/// `[program, "arg string"]` in a profile file.
+#[derive(Deserialize, Debug, Clone)]
+struct AteTemplate(String, String);
+
+/// Profile read from a JSON file. Templates refer to per-band variables as
+/// `$name`; `$phy` is the resolved phy index. See `profiles/siwifi.json`.
+///
+/// Every key of `bands` is a band. `freq` is its default channel; bands other
+/// than HB, LB and 6G also need `freq_min`, `freq_max` and `max_bw` (MHz).
+#[derive(Deserialize, Debug, Clone)]
+pub struct GenericProfile {
+    name: String,
+    /// Register map file, relative to the profile. Defaults to the siwifi map.
+    #[serde(default)]
+    regmap: Option<String>,
+    bands: BTreeMap<String, HashMap<String, String>>,
+    /// `$bw_code` for each bandwidth in MHz; the MHz value itself if missing.
+    #[serde(default)]
+    bandwidth_codes: HashMap<String, String>,
+    phy_device: String,
+    iq_engine: String,
+    bind: String,
+    unbind: String,
+    iface_up: AteTemplate,
+    open_rx: AteTemplate,
+    close_rx: AteTemplate,
+    #[serde(skip)]
+    register_map: Option<RegisterMap>,
+}
+
+impl GenericProfile {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read chip profile {}", path.display()))?;
+        let mut profile = Self::from_json(&text)
+            .with_context(|| format!("Invalid chip profile {}", path.display()))?;
+        if let Some(regmap) = &profile.regmap {
+            let regmap = path.parent().unwrap_or(Path::new(".")).join(regmap);
+            profile.register_map = Some(RegisterMap::load(regmap)?);
+        }
+        Ok(profile)
+    }
+
+    pub fn from_json(text: &str) -> anyhow::Result<Self> {
+        let profile: GenericProfile = serde_json::from_str(text)?;
+        if profile.bands.is_empty() {
+            return Err(anyhow!("Profile {} has no bands", profile.name));
+        }
+        for (name, vars) in &profile.bands {
+            let band = name.parse()?;
+            Self::plan(band, vars)
+                .and_then(|_| Self::capture_len(vars))
+                .with_context(|| format!("Profile {} band {}", profile.name, band))?;
+        }
+        Ok(profile)
+    }
+
+    /// The built-in plan of `band` with the limits set in `vars` replaced.
+    fn plan(band: Band, vars: &HashMap<String, String>) -> anyhow::Result<BandPlan> {
+        let num = |name: &str| vars.get(name)
+            .map(|value| value.parse::<u32>().map_err(|_| anyhow!("Invalid {} {:?}", name, value)))
+            .transpose();
+        let builtin = BandPlan::builtin(band);
+        let (min, max, max_bw) = match (num("freq_min")?, num("freq_max")?, &builtin) {
+            (Some(min), Some(max), _) => (min, max, None),
+            (min, max, Some(plan)) => (min.unwrap_or(*plan.freqs.start()), max.unwrap_or(*plan.freqs.end()),
+                                       Some(plan.max_bandwidth)),
+            _ => return Err(anyhow!("freq_min and freq_max are needed for a band other than HB, LB and 6G")),
+        };
+        let max_bandwidth = match num("max_bw")? {
+            Some(mhz) => Bandwidth::from_mhz(mhz)?,
+            None => max_bw.unwrap_or_default(),
+        };
+        let default_freq = num("freq")?
+            .or(builtin.map(|plan| plan.default_freq).filter(|freq| (min..=max).contains(freq)))
+            .unwrap_or(min);
+        if !(min..=max).contains(&default_freq) {
+            return Err(anyhow!("Default channel {} MHz is outside {}..={} MHz", default_freq, min, max));
+        }
+        Ok(BandPlan { freqs: min..=max, max_bandwidth, default_freq })
+    }
+
+    /// `capture_len` variable of a band (hex), checked like `IqEngineConfig::validate`.
+    fn capture_len(vars: &HashMap<String, String>) -> anyhow::Result<Option<u32>> {
+        let Some(len) = vars.get("capture_len") else { return Ok(None) };
+        let capture_len = u32::from_str_radix(len.trim_start_matches("0x"), 16)
+            .map_err(|_| anyhow!("Invalid capture_len {:?}", len))?;
+        IqEngineConfig::new(capture_len).validate()?;
+        Ok(Some(capture_len))
+    }
+
+    /// Expand `template` with the band's variables, `extra` taking precedence.
+    fn fill(&self, template: &str, band: Band, extra: &[(&str, String)]) -> String {
+        let mut vars = self.bands.get(band.name()).cloned().unwrap_or_default();
+        vars.insert("band".to_string(), band.to_string());
+        vars.extend(extra.iter().map(|(name, value)| (name.to_string(), value.clone())));
+        substitute(template, &vars)
+    }
+
+    fn ate(&self, template: &AteTemplate, band: Band, extra: &[(&str, String)]) -> AteCall {
+        (template.0.clone(), split_args(&self.fill(&template.1, band, extra)))
+    }
+}
+
+impl ChipProfile for GenericProfile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn bands(&self) -> Vec<Band> {
+        self.bands.keys().filter_map(|name| name.parse().ok()).collect()
+    }
+
+    fn band_plan(&self, band: Band) -> Option<BandPlan> {
+        Self::plan(band, self.bands.get(band.name())?).ok()
+    }
+
+    fn phy_device(&self, band: Band) -> String {
+        self.fill(&self.phy_device, band, &[])
+    }
+
+    /// `capture_len` of the band's variables (hex), the rest as on siwifi.
+    fn iq_engine_default(&self, band: Band) -> IqEngineConfig {
+        let capture_len = self.bands.get(band.name())
+            .and_then(|vars| Self::capture_len(vars).ok().flatten())
+            .unwrap_or(0xe000);
+        IqEngineConfig::new(capture_len)
+    }
+
+    fn iq_engine_cmd(&self, band: Band, phy: u8, config: &IqEngineConfig) -> String {
+        self.fill(&self.iq_engine, band, &[
+            ("phy", phy.to_string()),
+            ("iq_args", config.to_args()),
+            ("capture_len", format!("{:x}", config.capture_len)),
+        ])
+    }
+
+    fn unbind_cmd(&self, band: Band) -> String {
+        self.fill(&self.unbind, band, &[])
+    }
+
+    fn bind_cmd(&self, band: Band) -> String {
+        self.fill(&self.bind, band, &[])
+    }
+
+    fn iface_up(&self, band: Band) -> AteCall {
+        self.ate(&self.iface_up, band, &[])
+    }
+
+    fn open_rx(&self, band: Band, config: &RxConfig) -> AteCall {
+        let bw = config.bandwidth.mhz().to_string();
+        let bw_code = self.bandwidth_codes.get(&bw).cloned().unwrap_or_else(|| bw.clone());
+        let (cmd, mut args) = self.ate(&self.open_rx, band, &[
+            ("freq", config.freq.to_string()),
+            ("center", config.center().to_string()),
+            ("bw", bw),
+            ("bw_code", bw_code),
+        ]);
+        args.extend(config.extra_args.iter().cloned());
+        (cmd, args)
+    }
+
+    fn close_rx(&self, band: Band) -> AteCall {
+        self.ate(&self.close_rx, band, &[])
+    }
+
+    fn register_map(&self) -> RegisterMap {
+        self.register_map.clone().unwrap_or_else(RegisterMap::siwifi)
+    }
+}
+
+fn split_args(args: &str) -> Vec<String> {
+    args.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Band, Bandwidth, GainType, IqEngineConfig, RxConfig, TestBand};
+    use crate::mock::{mock_dut, mock_dut_with, MockBoard};
+    use crate::profile::{ChipProfile, GenericProfile, SiwifiProfile};
+    use crate::rfmetrics::FileParser;
+    use crate::testcase::TestCase;
+
+    #[test]
+    fn test_generic_profile_matches_siwifi() {
+        let generic = GenericProfile::load(concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/siwifi.json")).unwrap();
+        let siwifi = SiwifiProfile;
+        assert_eq!(generic.bands(), siwifi.bands());
+        for band in siwifi.bands() {
+            assert_eq!(generic.band_plan(band), siwifi.band_plan(band));
+            assert_eq!(generic.phy_device(band), siwifi.phy_device(band));
+            assert_eq!(generic.iq_engine_default(band), siwifi.iq_engine_default(band));
+            let config = IqEngineConfig { decimation: 1, ..siwifi.iq_engine_default(band) };
+            assert_eq!(generic.iq_engine_cmd(band, 3, &config), siwifi.iq_engine_cmd(band, 3, &config));
+            assert_eq!(generic.unbind_cmd(band), siwifi.unbind_cmd(band));
+            assert_eq!(generic.bind_cmd(band), siwifi.bind_cmd(band));
+            assert_eq!(generic.iface_up(band), siwifi.iface_up(band));
+            let vht80 = RxConfig {
+                center: Some(5530), bandwidth: Bandwidth::Bw80, extra_args: vec!["-g".into(), "1".into()], ..RxConfig::new(5500)
+            };
+            let default = RxConfig::default_for(&siwifi.band_plan(band).unwrap());
+            assert_eq!(generic.open_rx(band, &default), siwifi.open_rx(band, &default));
+            assert_eq!(generic.open_rx(band, &vht80), siwifi.open_rx(band, &vht80));
+            assert_eq!(generic.close_rx(band), siwifi.close_rx(band));
+        }
+        assert_eq!(generic.register_map().addresses(), siwifi.register_map().addresses());
+    }
+
+    #[test]
+    fn test_dut_uses_profile() {
+        let profile = GenericProfile::from_json(r#"{
+            "name": "other",
+            "bands": { "HB": { "iface": "ra0", "dev": "soc:wifi5g" }, "LB": { "iface": "ra1", "dev": "soc:wifi2g" } },
+            "phy_device": "$dev",
+            "iq_engine": "iqcap -p $phy -b $band",
+            "bind": "echo $dev > /sys/bus/platform/drivers/mtwifi/bind",
+            "unbind": "echo $dev > /sys/bus/platform/drivers/mtwifi/unbind",
+            "iface_up": ["ifconfig", "$iface up"],
+            "open_rx": ["iwpriv", "$iface set ATE=RXFRAME"],
+            "close_rx": ["iwpriv", "$iface set ATE=ATESTOP"]
+        }"#).unwrap();
+        let (board, mut dut, _dump_dir) = mock_dut("profile");
+        dut.set_profile(Box::new(profile));
+        dut.shut_up_band(Band::LB).unwrap();
+        dut.open_rx(Band::LB).unwrap();
+        dut.dump_iq(Band::LB, "LB_iq_0_0_00.txt".to_string()).unwrap();
+
+        let board = board.lock().unwrap();
+        assert_eq!(board.shell_log[0], "echo soc:wifi2g > /sys/bus/platform/drivers/mtwifi/bind");
+        assert!(board.shell_log.contains(&"iqcap -p 2 -b LB".to_string()), "{:?}", board.shell_log);
+        assert_eq!(board.ate_log, vec!["ifconfig ra1 up", "iwpriv ra1 set ATE=RXFRAME"]);
+    }
+
+    #[test]
+    fn test_third_band() {
+        let profile = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/siwifi.json")).unwrap();
+        let mut profile: serde_json::Value = serde_json::from_str(&profile).unwrap();
+        profile["regmap"] = serde_json::Value::Null;
+        profile["bands"]["6G"] = serde_json::json!({ "wmac": "40000000.wmac", "iface": "wlan2", "capture_len": "e000" });
+        for len in ["e0g0", "0", ""] {
+            let mut broken = profile.clone();
+            broken["bands"]["6G"]["capture_len"] = len.into();
+            assert!(GenericProfile::from_json(&broken.to_string()).is_err(), "{}", len);
+        }
+        let profile = GenericProfile::from_json(&profile.to_string()).unwrap();
+        let mut regmap: serde_json::Value = serde_json::from_str(include_str!("../regmap/siwifi.json")).unwrap();
+        regmap["registers"]["agc_override_6G"] = serde_json::json!({ "addr": "0x40c02f88", "layout": "agc_override" });
+        let mut board = MockBoard::default();
+        board.phys.insert(5, "40000000.wmac".into());
+        let (board, mut dut, dump_dir) = mock_dut_with("6g", board);
+        let regmap_path = dump_dir.join("regmap_6g.json");
+        std::fs::write(&regmap_path, regmap.to_string()).unwrap();
+
+        dut.set_profile(Box::new(profile));
+        dut.load_regmap(regmap_path.to_str().unwrap()).unwrap();
+        let six = dut.band("6G").unwrap();
+        assert_eq!(dut.bands(), vec![six, Band::HB, Band::LB]);
+        assert_eq!(dut.band_plan(six).unwrap().default_freq, 5955);
+        assert!(dut.band("5G").is_err());
+
+        let mut shut_down = Vec::new();
+        dut.isolate_band(six, &mut shut_down).unwrap();
+        assert_eq!(shut_down, vec![Band::HB, Band::LB]);
+        dut.open_rx(six).unwrap();
+        assert!(dut.open_rx_with(six, &RxConfig::new(5180)).is_err());
+        TestBand::new(six, GainType::Vga((0..2).collect())).run_test(&mut dut);
+
+        assert!(dump_dir.join("6G_iq_0_0_01.txt").exists());
+        let mut parser = FileParser::new(dut.file_list.file_list.clone()).with_bands(dut.bands()).sort_file();
+        parser.write_workbook(&dump_dir.join("result.xlsx")).unwrap();
+
+        // a server whose DumpIQ only knows band_5g must not silently capture LB
+        board.lock().unwrap().capabilities.as_mut().unwrap().commands.retain(|c| c != "DumpIQBand");
+        dut.reconnect().unwrap();
+        let commands = board.lock().unwrap().shell_log.len();
+        let err = dut.dump_iq(six, "6G_iq_0_0_02.txt".into()).unwrap_err();
+        assert!(err.to_string().contains("not 6G"), "{}", err);
+        // refused before the capture engine is touched
+        assert_eq!(board.lock().unwrap().shell_log.len(), commands);
+        let board = board.lock().unwrap();
+        assert!(board.shell_log.iter().any(|cmd| cmd.ends_with("/phy5/siwifi/iq_engine")), "{:?}", board.shell_log);
+        assert_eq!(board.ate_log.last().unwrap(), "ate_cmd wlan2 fastconfig -f 5955 -c 5955 -w 1 -u 1 -r");
+        assert_eq!(board.regs.get(&0x40c02f88), Some(&0x34021402));
+    }
+}
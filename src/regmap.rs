@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+/// Register map of the siwifi chips, used unless another one is loaded.
+const SIWIFI_REGMAP: &str = include_str!("../regmap/siwifi.json");
+
+/// Bitfield `[lsb, lsb + width)` of a register.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Field {
+    pub lsb: u8,
+    pub width: u8,
+}
+
+impl Field {
+    fn mask(&self) -> u32 {
+        (((1u64 << self.width) - 1) as u32) << self.lsb
+    }
+
+    /// Put `value` into the field of `word`, rejecting values wider than the field.
+    pub fn insert(&self, word: u32, value: u32) -> anyhow::Result<u32> {
+        if self.width < 32 && value >> self.width != 0 {
+            return Err(anyhow!("Value {} does not fit in a {} bit field", value, self.width));
+        }
+        Ok(word & !self.mask() | (value << self.lsb) & self.mask())
+    }
+
+    pub fn extract(&self, word: u32) -> u32 {
+        (word & self.mask()) >> self.lsb
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct RegisterDef {
+    addr: Value,
+    #[serde(default)]
+    layout: Option<String>,
+    #[serde(default)]
+    fields: BTreeMap<String, Field>,
+}
+
+/// A register number: JSON integer, `"0x..."` string or `"$param"` reference.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Value {
+    Num(u32),
+    Text(String),
+}
+
+impl Value {
+    fn resolve(&self, params: &HashMap<String, String>) -> anyhow::Result<u32> {
+        match self {
+            Value::Num(n) => Ok(*n),
+            Value::Text(text) => parse_u32(&substitute(text, params)),
+        }
+    }
+}
+
+/// One write of a sequence: a whole `value`, or `fields` composed onto zero.
+#[derive(Deserialize, Debug, Clone)]
+struct Step {
+    reg: String,
+    #[serde(default)]
+    value: Option<Value>,
+    #[serde(default)]
+    fields: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RegisterMap {
+    #[serde(default)]
+    layouts: HashMap<String, BTreeMap<String, Field>>,
+    registers: HashMap<String, RegisterDef>,
+    #[serde(default)]
+    sequences: HashMap<String, Vec<Step>>,
+}
+
+/// A register resolved from the map.
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub name: String,
+    pub addr: u32,
+    pub fields: BTreeMap<String, Field>,
+}
+
+impl Register {
+    pub fn field(&self, name: &str) -> anyhow::Result<Field> {
+        self.fields.get(name).copied()
+            .ok_or_else(|| anyhow!("Register {} has no field {}", self.name, name))
+    }
+}
+
+impl RegisterMap {
+    pub fn siwifi() -> Self {
+        Self::from_json(SIWIFI_REGMAP).expect("builtin register map is valid")
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read register map {}", path.display()))?;
+        Self::from_json(&text).with_context(|| format!("Invalid register map {}", path.display()))
+    }
+
+    /// Parse a map and check every layout, register and field a sequence refers to.
+    pub fn from_json(text: &str) -> anyhow::Result<Self> {
+        let map: RegisterMap = serde_json::from_str(text)?;
+        let all_fields = map.layouts.iter()
+            .flat_map(|(name, fields)| fields.iter().map(move |f| (name, f)))
+            .chain(map.registers.iter().flat_map(|(name, def)| def.fields.iter().map(move |f| (name, f))));
+        for (owner, (field, def)) in all_fields {
+            if def.width == 0 || def.lsb as u32 + def.width as u32 > 32 {
+                return Err(anyhow!("Field {}.{} does not fit in 32 bits", owner, field));
+            }
+        }
+        for (name, def) in &map.registers {
+            if let Value::Text(addr) = &def.addr {
+                parse_u32(addr).with_context(|| format!("Register {}", name))?;
+            }
+            if let Some(layout) = def.layout.as_ref().filter(|layout| !map.layouts.contains_key(*layout)) {
+                return Err(anyhow!("Register {} uses unknown layout {}", name, layout));
+            }
+        }
+        for (name, steps) in &map.sequences {
+            for step in steps {
+                if step.value.is_none() && step.fields.is_empty() {
+                    return Err(anyhow!("Sequence {} writes {} without value or fields", name, step.reg));
+                }
+                if !step.reg.contains('$') {
+                    let reg = map.register(&step.reg).with_context(|| format!("Sequence {}", name))?;
+                    for field in step.fields.keys() {
+                        reg.field(field).with_context(|| format!("Sequence {}", name))?;
+                    }
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn register(&self, name: &str) -> anyhow::Result<Register> {
+        let def = self.registers.get(name)
+            .ok_or_else(|| anyhow!("Unknown register {}", name))?;
+        let mut fields = def.layout.as_ref()
+            .and_then(|layout| self.layouts.get(layout))
+            .cloned()
+            .unwrap_or_default();
+        fields.extend(def.fields.clone());
+        Ok(Register {
+            name: name.to_string(),
+            addr: def.addr.resolve(&HashMap::new())?,
+            fields,
+        })
+    }
+
+    /// Addresses of every register in the map, sorted.
+    pub fn addresses(&self) -> Vec<u32> {
+        let mut addrs: Vec<u32> = self.registers.values()
+            .filter_map(|def| def.addr.resolve(&HashMap::new()).ok())
+            .collect();
+        addrs.sort_unstable();
+        addrs.dedup();
+        addrs
+    }
+
+    /// Expand a named sequence into `(addr, value)` writes. `$name` in register
+    /// names and values is replaced by `params[name]`.
+    pub fn sequence(&self, name: &str, params: &HashMap<String, String>) -> anyhow::Result<Vec<(u32, u32)>> {
+        let steps = self.sequences.get(name)
+            .ok_or_else(|| anyhow!("Unknown register sequence {}", name))?;
+        steps.iter()
+            .map(|step| {
+                let reg = self.register(&substitute(&step.reg, params))?;
+                let mut value = match &step.value {
+                    Some(value) => value.resolve(params)?,
+                    None => 0,
+                };
+                for (field, field_value) in &step.fields {
+                    value = reg.field(field)?.insert(value, field_value.resolve(params)?)
+                        .with_context(|| format!("{}.{}", reg.name, field))?;
+                }
+                Ok((reg.addr, value))
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn substitute(text: &str, params: &HashMap<String, String>) -> String {
+    let mut text = text.to_string();
+    // longest names first so `$fem` does not eat the start of `$fem_base`
+    let mut names: Vec<_> = params.keys().collect();
+    names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+    for name in names {
+        text = text.replace(&format!("${}", name), &params[name]);
+    }
+    text
+}
+
+fn parse_u32(text: &str) -> anyhow::Result<u32> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| anyhow!("Invalid number {:?}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::client::pack_bit;
+    use crate::regmap::RegisterMap;
+
+    #[test]
+    fn test_fix_gain_sequence_matches_pack_bit() {
+        let map = RegisterMap::siwifi();
+        let params = HashMap::from([
+            ("band".to_string(), "LB".to_string()),
+            ("fem".to_string(), "1".to_string()),
+            ("lna".to_string(), "5".to_string()),
+            ("vga".to_string(), "17".to_string()),
+        ]);
+        let gain = pack_bit(1, 5, 17) as u32;
+        assert_eq!(map.sequence("fix_gain", &params).unwrap(), vec![
+            (0x20c02f88, 0x2d170d17),
+            (0x20c02f88, 0x3d171d17),
+            (0x20c02f88, (gain | 0x2000) << 16 | gain),
+            (0x20c02f88, (gain | 0x3000) << 16 | (gain | 0x1000)),
+        ]);
+
+        let params = HashMap::from([
+            ("band".to_string(), "HB".to_string()),
+            ("fem".to_string(), "0".to_string()),
+            ("lna".to_string(), "8".to_string()),
+            ("vga".to_string(), "0".to_string()),
+        ]);
+        assert!(map.sequence("fix_gain", &params).is_err());
+    }
+
+    #[test]
+    fn test_reject_unknown_field() {
+        let text = r#"{"registers": {"r": {"addr": "0x10"}},
+                       "sequences": {"s": [{"reg": "r", "fields": {"nope": 1}}]}}"#;
+        assert!(RegisterMap::from_json(text).is_err());
+    }
+}
@@ -1,22 +1,26 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::f64::consts::PI;
 use std::fs;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
+use anyhow::Context;
 use num_complex::Complex64;
 use rust_xlsxwriter::{ColNum, Color, Format, FormatAlign, RowNum, Workbook, Worksheet};
+use pyo3::{pyclass, pymethods};
 use rustfft::FftPlanner;
 use crate::config::Band;
 
-#[derive(Debug)]
-struct RfMetrics {
-    fund_freq: f64,
-    fund_power: f64,
-    total_power: f64,
-    channel_power: f64,
-    snr: f64,
-    sfdr: f64,
-    noise_per_hz: f64
+#[pyclass(get_all)]
+#[derive(Debug, Clone)]
+pub(crate) struct RfMetrics {
+    pub(crate) fund_freq: f64,
+    pub(crate) fund_power: f64,
+    pub(crate) total_power: f64,
+    pub(crate) channel_power: f64,
+    pub(crate) snr: f64,
+    pub(crate) sfdr: f64,
+    pub(crate) noise_per_hz: f64
 }
 
 impl RfMetrics {
@@ -31,9 +35,74 @@ impl RfMetrics {
             noise_per_hz,
         }
     }
+
+    /// Column names of `values`, in order.
+    pub(crate) const FIELDS: [&'static str; 7] =
+        ["Fund_freq", "Fund_power", "Total_power", "Channel_power", "SNR", "SFDR", "Noise_per_Hz"];
+    /// Power fields in dB, averaged as power and checked against the spread
+    /// threshold, as indexes into `FIELDS`.
+    const POWER_FIELDS: [usize; 4] = [1, 2, 3, 6];
+
+    pub(crate) fn values(&self) -> [f64; 7] {
+        [self.fund_freq, self.fund_power, self.total_power, self.channel_power, self.snr, self.sfdr, self.noise_per_hz]
+    }
+
+    fn from_values([fund_freq, fund_power, total_power, channel_power, snr, sfdr, noise_per_hz]: [f64; 7]) -> Self {
+        Self::new(fund_freq, fund_power, total_power, channel_power, snr, sfdr, noise_per_hz)
+    }
+
+    /// Statistics of every field over repeated captures, in `FIELDS` order.
+    /// Power fields are averaged as power, not as dB.
+    pub(crate) fn stats(captures: &[RfMetrics]) -> [Stats; 7] {
+        std::array::from_fn(|field| {
+            let values: Vec<f64> = captures.iter().map(|m| m.values()[field]).collect();
+            if Self::POWER_FIELDS.contains(&field) {
+                Stats::of_db(&values)
+            } else {
+                Stats::of(&values)
+            }
+        })
+    }
+}
+
+/// Mean, sample standard deviation, min and max of one metric over repeated captures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) mean: f64,
+    pub(crate) std: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+}
+
+impl Stats {
+    pub(crate) fn of(values: &[f64]) -> Self {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let var = if values.len() > 1 {
+            values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+        Self {
+            mean,
+            std: var.sqrt(),
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+
+    /// `of` for dB values, with the mean taken in linear units.
+    pub(crate) fn of_db(values: &[f64]) -> Self {
+        let linear = values.iter().map(|v| 10f64.powf(v / 10.0)).sum::<f64>() / values.len() as f64;
+        Self { mean: 10.0 * linear.log10(), ..Self::of(values) }
+    }
+
+    pub(crate) fn spread(&self) -> f64 {
+        self.max - self.min
+    }
 }
 
-trait CalcMetric {
+pub(crate) trait CalcMetric {
     fn get_iq_data(&self) -> (Vec<i16>, Vec<i16>, u8);
     fn calc_metric(&self) -> RfMetrics {
         let (i_data, q_data, fs) = self.get_iq_data();
@@ -297,8 +366,85 @@ impl CalcMetric for (Vec<i16>, Vec<i16>, u8) {
     }
 }
 
+/// Gain label and both paths' metrics of each dump on one sheet.
+type SheetRows = Vec<(String, (RfMetrics, RfMetrics))>;
+
+/// Gain label and every capture of that point, in file order.
+type PointCaptures = Vec<(String, Vec<(RfMetrics, RfMetrics)>)>;
+
+/// Default spread, in dB, above which repeated captures of a point are flagged.
+pub const DEFAULT_SPREAD_THRESHOLD: f64 = 1.0;
+
+/// (i, q) samples of one receive path.
+pub(crate) type IqPath = (Vec<i16>, Vec<i16>);
+
+/// Both receive paths of one IQ dump, held in memory.
+#[pyclass(get_all)]
+#[derive(Debug, Clone)]
+pub struct IqCapture {
+    pub(crate) path1: IqPath,
+    pub(crate) path2: IqPath,
+}
+
+impl IqCapture {
+    pub(crate) fn parse(reader: impl BufRead) -> anyhow::Result<Self> {
+        let (path1, path2) = FileParser::parse_iq(reader)?;
+        if path1.0.is_empty() || path2.0.is_empty() {
+            anyhow::bail!("IQ dump has no samples for both paths");
+        }
+        Ok(Self { path1, path2 })
+    }
+
+    pub(crate) fn calc_metrics(&self, fs: u8) -> (RfMetrics, RfMetrics) {
+        let res1 = (self.path1.0.clone(), self.path1.1.clone(), fs).calc_metric();
+        let res2 = (self.path2.0.clone(), self.path2.1.clone(), fs).calc_metric();
+        (res1, res2)
+    }
+}
+
+#[pymethods]
+impl IqCapture {
+    #[pyo3(signature = (fs=40))]
+    fn metrics(&self, fs: u8) -> (RfMetrics, RfMetrics) {
+        self.calc_metrics(fs)
+    }
+}
+
+/// Band, channel and gain encoded in a dump file name: `HB_iq_0_3_10.txt`, or
+/// `HB_ch5180_iq_0_3_10.txt` for a channel sweep; repeated captures end in `_r{k}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DumpName {
+    pub(crate) band: String,
+    pub(crate) channel: Option<u32>,
+    /// `{fem}_{lna}_{vga}`
+    pub(crate) gain: String,
+    pub(crate) repeat: Option<u32>,
+}
+
+impl DumpName {
+    pub(crate) fn parse(file: &str) -> Option<Self> {
+        let (prefix, gain) = file.strip_suffix(".txt")?.split_once("_iq_")?;
+        let (band, channel) = match prefix.split_once("_ch") {
+            Some((band, ch)) => (band, Some(ch.parse().ok()?)),
+            None => (prefix, None),
+        };
+        let (gain, repeat) = match gain.split_once("_r") {
+            Some((gain, k)) => (gain, Some(k.parse().ok()?)),
+            None => (gain, None),
+        };
+        Some(Self { band: band.to_string(), channel, gain: gain.to_string(), repeat })
+    }
+}
+
 pub(crate) struct FileParser {
     pub(crate) file_list: Vec<String>,
+    /// Bands that get a sheet even without dumps; files of other bands are skipped.
+    bands: Vec<Band>,
+    /// Repeated points whose power spreads more than this many dB are flagged.
+    spread_threshold: f64,
+    /// Metrics already computed during the sweep, by file, so the workbook
+    /// does not parse and FFT those dumps again.
+    pub(crate) metrics: HashMap<String, (RfMetrics, RfMetrics)>,
     workbook: Workbook
 }
 
@@ -307,12 +453,53 @@ impl FileParser {
         let workbook = Workbook::new();
         Self {
             file_list,
+            bands: vec![Band::HB, Band::LB],
+            spread_threshold: DEFAULT_SPREAD_THRESHOLD,
+            metrics: HashMap::new(),
             workbook
         }
     }
 
+    pub(crate) fn with_spread_threshold(mut self, db: f64) -> Self {
+        self.spread_threshold = db;
+        self
+    }
+
+    pub(crate) fn with_bands(mut self, bands: Vec<Band>) -> Self {
+        self.bands = bands;
+        self
+    }
+
     pub fn add_file(&mut self, filename: String) {
-        self.file_list.push(filename);
+        // a retried gain point copies the same file again
+        self.metrics.remove(&filename);
+        if !self.file_list.contains(&filename) {
+            self.file_list.push(filename);
+        }
+    }
+
+    /// Remember the metrics of a file in the list.
+    pub(crate) fn add_metrics(&mut self, filename: String, metrics: (RfMetrics, RfMetrics)) {
+        self.metrics.insert(filename, metrics);
+    }
+
+    pub(crate) fn with_metrics(mut self, metrics: HashMap<String, (RfMetrics, RfMetrics)>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Also give a sheet to every band found in the dump file names, for
+    /// dumps whose profile is not at hand.
+    pub(crate) fn with_bands_from_files(mut self) -> Self {
+        for file in &self.file_list {
+            let band = Path::new(file).file_name()
+                .and_then(|name| DumpName::parse(name.to_str()?))
+                .and_then(|name| name.band.parse::<Band>().ok());
+            if let Some(band) = band.filter(|band| !self.bands.contains(band)) {
+                self.bands.push(band);
+            }
+        }
+        self
     }
 
     pub fn sort_file(mut self) -> Self {
@@ -320,40 +507,189 @@ impl FileParser {
         self
     }
 
-    pub fn parse_and_write(&mut self) -> anyhow::Result<()>{
-        self.write_band_excel(Band::HB)?;
-        self.write_band_excel(Band::LB)?;
+    /// Write `result.xlsx` into `dir`.
+    pub fn parse_and_write(&mut self, dir: &Path) -> anyhow::Result<()>{
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+        }
+        let path = dir.join("result.xlsx");
+        self.write_workbook(&path).with_context(|| format!("write {}", path.display()))
+    }
+
+    pub(crate) fn write_workbook(&mut self, path: &Path) -> anyhow::Result<()> {
+        // one sheet per band and channel; the plain band sheets are always written
+        let mut sheets: BTreeMap<(String, Option<u32>), PointCaptures> = BTreeMap::new();
+        for band in &self.bands {
+            sheets.entry((band.to_string(), None)).or_default();
+        }
+        for f in &self.file_list {
+            let file = Path::new(f)
+                .file_name()
+                .and_then(|x| x.to_str())
+                .unwrap();
+            let Some(name) = DumpName::parse(file) else {
+                log::warn!("Skip {}, not an IQ dump file name", f);
+                continue;
+            };
+            if !sheets.contains_key(&(name.band.clone(), None)) {
+                log::warn!("Skip {}, unknown band {}", f, name.band);
+                continue;
+            }
+            let res = match self.metrics.get(f) {
+                Some(res) => res.clone(),
+                None => match Self::parse_file(f, 40) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        log::warn!("Skip {}: {:#}", f, e);
+                        continue;
+                    }
+                },
+            };
+            let points = sheets.entry((name.band, name.channel)).or_default();
+            match points.iter_mut().find(|(gain, _)| *gain == name.gain) {
+                Some((_, captures)) => captures.push(res),
+                None => points.push((name.gain, vec![res])),
+            }
+        }
+
+        // repeated points show their mean; the spread goes to a `_stats` sheet
+        let mut means: BTreeMap<(String, Option<u32>), SheetRows> = BTreeMap::new();
+        for ((band, channel), points) in sheets {
+            let sheet_name = match channel {
+                Some(ch) => format!("{}_{}", band, ch),
+                None => band.clone(),
+            };
+            let rows: SheetRows = points.iter().map(|(gain, captures)| (gain.clone(), mean(captures))).collect();
+            Self::write_sheet(self.workbook.add_worksheet(), &sheet_name, &rows)?;
+            if points.iter().any(|(_, captures)| captures.len() > 1) {
+                let sheet = self.workbook.add_worksheet();
+                Self::write_stats(sheet, &points, self.spread_threshold)?;
+                sheet.set_name(format!("{}_stats", sheet_name))?;
+            }
+            means.insert((band, channel), rows);
+        }
+        let channel_bands: BTreeSet<&String> = means.keys()
+            .filter(|(_, channel)| channel.is_some())
+            .map(|(band, _)| band)
+            .collect();
+        for band in channel_bands {
+            let channels: Vec<_> = means.iter()
+                .filter_map(|((b, ch), rows)| Some((ch.filter(|_| b == band)?, rows)))
+                .collect();
+            let sheet = self.workbook.add_worksheet();
+            Self::write_flatness(sheet, &channels)?;
+            sheet.set_name(format!("{}_flatness", band))?;
+        }
+
+        self.workbook.save(path)?;
+        Ok(())
+    }
 
-        if !Path::new("./iq_dump").exists() {
-            fs::create_dir_all("./iq_dump")?;
+    fn write_sheet(sheet: &mut Worksheet, name: &str, rows: &SheetRows) -> anyhow::Result<()> {
+        Self::write_header(sheet)?;
+        for (line, (gain, res)) in rows.iter().enumerate() {
+            Self::write_excel(sheet, line as RowNum + 2, res.clone(), gain)?;
         }
-        self.workbook.save("iq_dump/result.xlsx")?;
+        log::info!("{} has {} cases", name, rows.len());
+        sheet.set_name(name)?;
         Ok(())
     }
 
-    fn write_band_excel(&mut self, band: Band) -> anyhow::Result<()> {
-        let mut line = 2;
-        let sheet = self.workbook.add_worksheet();
+    /// Fund power of every gain point on every channel of a band, with the
+    /// spread across channels per path.
+    fn write_flatness(sheet: &mut Worksheet, channels: &[(u32, &SheetRows)]) -> anyhow::Result<()> {
+        let header_format = Format::new()
+            .set_bold()
+            .set_align(FormatAlign::Center)
+            .set_background_color(Color::Gray);
+        let gains: BTreeSet<&String> = channels.iter()
+            .flat_map(|(_, rows)| rows.iter().map(|(gain, _)| gain))
+            .collect();
+        let width = channels.len() as ColNum + 1;
+
+        sheet.set_column_width(0, 32)?;
+        sheet.write_with_format(0, 0, "Gain\n(fem-lna-vga)", &header_format)?;
+        for (path, first_col) in [("Path1", 1), ("Path2", 1 + width)] {
+            for (idx, (ch, _)) in channels.iter().enumerate() {
+                sheet.set_column_width(first_col + idx as ColNum, 16)?;
+                sheet.write_with_format(0, first_col + idx as ColNum, format!("{} {} MHz", path, ch), &header_format)?;
+            }
+            sheet.set_column_width(first_col + width - 1, 16)?;
+            sheet.write_with_format(0, first_col + width - 1, format!("{} spread", path), &header_format)?;
+        }
 
-        Self::write_header(sheet)?;
+        for (line, gain) in gains.iter().enumerate() {
+            let line = line as RowNum + 1;
+            sheet.write(line, 0, gain.as_str())?;
+            for (path, first_col) in [(0, 1), (1, 1 + width)] {
+                let mut powers = Vec::new();
+                for (idx, (_, rows)) in channels.iter().enumerate() {
+                    if let Some((_, res)) = rows.iter().find(|(g, _)| g == *gain) {
+                        let power = if path == 0 { res.0.fund_power } else { res.1.fund_power };
+                        sheet.write(line, first_col + idx as ColNum, power)?;
+                        powers.push(power);
+                    }
+                }
+                if !powers.is_empty() {
+                    let max = powers.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                    let min = powers.iter().copied().fold(f64::INFINITY, f64::min);
+                    sheet.write(line, first_col + width - 1, max - min)?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let band_name = format!("{}", band);
-        self.file_list.iter()
-            .for_each(|f| {
-                let file = Path::new(f)
-                    .file_name()
-                    .and_then(|x| x.to_str())
-                    .unwrap();
-                if file.starts_with(&band_name) {
-                    // hb_iq_{fem}_{lna}_{vga}.txt
-                    let res = Self::parse_file(f, 40);
-                    // Some((f[6..12].into_string(), res))
-                    Self::write_excel(sheet, line, res, &file[6..12]).unwrap();
-                    line += 1;
+    /// Mean, std, min and max of every metric of each point, flagging points
+    /// whose power spread on either path exceeds `threshold` dB.
+    fn write_stats(sheet: &mut Worksheet, points: &PointCaptures, threshold: f64) -> anyhow::Result<()> {
+        let header_format = Format::new()
+            .set_bold()
+            .set_align(FormatAlign::Center)
+            .set_background_color(Color::Gray);
+        let flag_format = Format::new().set_background_color(Color::Red);
+        let columns = RfMetrics::FIELDS.len() as ColNum * 4;
+        let flag_col = 2 + 2 * columns;
+
+        sheet.set_column_width(0, 32)?;
+        sheet.merge_range(0, 0, 1, 0, "Gain\n(fem-lna-vga)", &header_format)?;
+        sheet.merge_range(0, 1, 1, 1, "Captures", &header_format)?;
+        for (path, first_col) in [("Path1", 2), ("Path2", 2 + columns)] {
+            for (idx, field) in RfMetrics::FIELDS.iter().enumerate() {
+                let col = first_col + idx as ColNum * 4;
+                sheet.merge_range(0, col, 0, col + 3, &format!("{} {}", path, field), &header_format)?;
+                for (offset, stat) in ["Mean", "Std", "Min", "Max"].iter().enumerate() {
+                    sheet.write_with_format(1, col + offset as ColNum, *stat, &header_format)?;
+                }
+            }
+        }
+        sheet.set_column_width(flag_col, 22)?;
+        sheet.merge_range(0, flag_col, 1, flag_col, &format!("Spread > {} dB", threshold), &header_format)?;
+
+        for (line, (gain, captures)) in points.iter().enumerate() {
+            let line = line as RowNum + 2;
+            let paths = [
+                RfMetrics::stats(&captures.iter().map(|res| res.0.clone()).collect::<Vec<_>>()),
+                RfMetrics::stats(&captures.iter().map(|res| res.1.clone()).collect::<Vec<_>>()),
+            ];
+            let flagged = spread_exceeds(&paths, threshold);
+            if flagged {
+                sheet.write_with_format(line, 0, gain.as_str(), &flag_format)?;
+                sheet.write_with_format(line, flag_col, "yes", &flag_format)?;
+                log::warn!("Gain {} spreads more than {} dB over {} captures", gain, threshold, captures.len());
+            } else {
+                sheet.write(line, 0, gain.as_str())?;
+            }
+            sheet.write(line, 1, captures.len() as u32)?;
+            for (stats, first_col) in paths.iter().zip([2, 2 + columns]) {
+                for (idx, stat) in stats.iter().enumerate() {
+                    let col = first_col + idx as ColNum * 4;
+                    for (offset, value) in [stat.mean, stat.std, stat.min, stat.max].into_iter().enumerate() {
+                        sheet.write(line, col + offset as ColNum, value)?;
+                    }
                 }
-            });
-        log::info!("{} has {} cases", band, line-2);
-        sheet.set_name(format!("{}", band))?;
+            }
+        }
         Ok(())
     }
 
@@ -406,41 +742,69 @@ impl FileParser {
 
     }
 
-    fn parse_file(filename: &str, fs: u8) -> (RfMetrics, RfMetrics) {
-        let file = File::open(filename).unwrap();
+    fn parse_file(filename: &str, fs: u8) -> anyhow::Result<(RfMetrics, RfMetrics)> {
+        let file = File::open(filename).with_context(|| format!("open {filename}"))?;
+        let capture = IqCapture::parse(BufReader::new(file)).with_context(|| format!("parse {filename}"))?;
+        if cfg!(test) {
+            println!("{:?}", capture.path1.0);
+            println!("{:?}", capture.path1.1);
+        }
+        Ok(capture.calc_metrics(fs))
+    }
+
+    /// Split an IQ dump into ((i, q) of path1, (i, q) of path2); sample lines alternate between paths.
+    pub(crate) fn parse_iq(reader: impl BufRead) -> anyhow::Result<(IqPath, IqPath)> {
         let mut i_data_path1 = Vec::new();
         let mut q_data_path1 = Vec::new();
         let mut i_data_path2 = Vec::new();
         let mut q_data_path2 = Vec::new();
         let mut temp_flag = true;
-        for line in BufReader::new(file).lines() {
-            let line = &line.unwrap();
+        for (n, line) in reader.lines().enumerate() {
+            let line = &line.with_context(|| format!("read line {}", n + 1))?;
+            if line.is_empty() || !line.starts_with("0x00") { continue; }
+            let (i, q) = Self::parse_sample(line).with_context(|| format!("bad sample on line {}: {line:?}", n + 1))?;
             if temp_flag {
-                if line.is_empty() { continue; }
-                if line.starts_with("0x00") {
-                    temp_flag = false;
-                    i_data_path1.push(hex12_to_i16(u16::from_str_radix(&line[7..10], 16).unwrap()));
-                    q_data_path1.push(hex12_to_i16(u16::from_str_radix(&line[4..7], 16).unwrap()));
-                    continue
-                }
+                i_data_path1.push(i);
+                q_data_path1.push(q);
             } else {
-                if line.is_empty() { continue; }
-                if line.starts_with("0x00") {
-                    temp_flag = true;
-                    i_data_path2.push(hex12_to_i16(u16::from_str_radix(&line[7..10], 16).unwrap()));
-                    q_data_path2.push(hex12_to_i16(u16::from_str_radix(&line[4..7], 16).unwrap()));
-                    continue
-                }
+                i_data_path2.push(i);
+                q_data_path2.push(q);
             }
+            temp_flag = !temp_flag;
         }
-        if cfg!(test) {
-            println!("{:?}", i_data_path1);
-            println!("{:?}", q_data_path1);
-        }
-        let res1 = (i_data_path1, q_data_path1, fs).calc_metric();
-        let res2 = (i_data_path2, q_data_path2, fs).calc_metric();
-        (res1, res2)
+        Ok(((i_data_path1, q_data_path1), (i_data_path2, q_data_path2)))
+    }
+
+    /// Decode one `0x00QQQIII` sample line into (i, q).
+    fn parse_sample(line: &str) -> anyhow::Result<(i16, i16)> {
+        let field = |range: std::ops::Range<usize>| -> anyhow::Result<i16> {
+            let hex = line.get(range).context("line too short")?;
+            Ok(hex12_to_i16(u16::from_str_radix(hex, 16)?))
+        };
+        Ok((field(7..10)?, field(4..7)?))
+    }
+}
+
+/// Field-wise mean of both paths over the captures of one point.
+fn mean(captures: &[(RfMetrics, RfMetrics)]) -> (RfMetrics, RfMetrics) {
+    let path = |pick: fn(&(RfMetrics, RfMetrics)) -> &RfMetrics| {
+        let metrics: Vec<RfMetrics> = captures.iter().map(|res| pick(res).clone()).collect();
+        RfMetrics::from_values(RfMetrics::stats(&metrics).map(|stat| stat.mean))
+    };
+    (path(|res| &res.0), path(|res| &res.1))
+}
+
+/// Reject a spread threshold that would flag every point or none.
+pub(crate) fn check_spread_threshold(db: f64) -> anyhow::Result<()> {
+    if db.is_nan() || db < 0.0 {
+        anyhow::bail!("spread_threshold must be a non-negative number of dB, got {}", db);
     }
+    Ok(())
+}
+
+/// Whether any power field of any path spreads more than `threshold` dB.
+fn spread_exceeds(paths: &[[Stats; 7]], threshold: f64) -> bool {
+    paths.iter().any(|stats| RfMetrics::POWER_FIELDS.iter().any(|&field| stats[field].spread() > threshold))
 }
 
 fn hex12_to_i16(value: u16) -> i16 {
@@ -454,7 +818,65 @@ fn hex12_to_i16(value: u16) -> i16 {
 
 #[cfg(test)]
 mod tests {
-    use crate::rfmetrics::FileParser;
+    use crate::rfmetrics::{check_spread_threshold, mean, spread_exceeds, DumpName, FileParser, IqCapture, RfMetrics, Stats};
+    use std::path::Path;
+
+    #[test]
+    fn test_dump_name() {
+        assert_eq!(DumpName::parse("HB_iq_0_3_10.txt"),
+                   Some(DumpName { band: "HB".into(), channel: None, gain: "0_3_10".into(), repeat: None }));
+        assert_eq!(DumpName::parse("LB_ch2437_iq_1_0_00.txt"),
+                   Some(DumpName { band: "LB".into(), channel: Some(2437), gain: "1_0_00".into(), repeat: None }));
+        assert_eq!(DumpName::parse("HB_ch5180_iq_1_3_10_r2.txt"),
+                   Some(DumpName { band: "HB".into(), channel: Some(5180), gain: "1_3_10".into(), repeat: Some(2) }));
+        assert_eq!(DumpName::parse("HB_iq_1_3_10_rx.txt"), None);
+        assert_eq!(DumpName::parse("result.xlsx"), None);
+    }
+
+    #[test]
+    fn test_parse_garbled_dump() {
+        assert!(FileParser::parse_iq("0x00abc123\n0x00zzz123\n".as_bytes()).is_err());
+        assert!(FileParser::parse_iq("0x00abc\n".as_bytes()).is_err());
+        assert!(IqCapture::parse("not an iq dump\n".as_bytes()).is_err());
+        let (path1, path2) = FileParser::parse_iq("0x00001fff\n\n0x00800001\n".as_bytes()).unwrap();
+        assert_eq!(path1, (vec![-1], vec![1]));
+        assert_eq!(path2, (vec![1], vec![-2048]));
+    }
+
+    #[test]
+    fn test_bands_from_files() {
+        let files = ["d/HB_iq_0_0_00.txt", "d/6G_ch5955_iq_0_0_00.txt", "d/6G_iq_0_0_01.txt", "d/notes.txt"];
+        let parser = FileParser::new(files.iter().map(|f| f.to_string()).collect()).with_bands_from_files();
+        assert_eq!(parser.bands.iter().map(|band| band.to_string()).collect::<Vec<_>>(), vec!["HB", "LB", "6G"]);
+    }
+
+    #[test]
+    fn test_repeat_stats() {
+        let stats = Stats::of(&[-30.0, -31.0, -32.0]);
+        assert_eq!((stats.mean, stats.std, stats.min, stats.max), (-31.0, 1.0, -32.0, -30.0));
+        assert_eq!(Stats::of(&[-30.0]).std, 0.0);
+        let stats = Stats::of_db(&[-30.0, -40.0]);
+        assert!((stats.mean + 32.596).abs() < 1e-3);
+        assert_eq!((stats.min, stats.max), (-40.0, -30.0));
+
+        let capture = |power: f64| {
+            let metrics = RfMetrics::new(1e6, power, power + 1.0, power + 0.5, 40.0, -50.0, power - 120.0);
+            (metrics.clone(), metrics)
+        };
+        let captures = [capture(-30.0), capture(-30.4), capture(-29.8)];
+        let (path1, _) = mean(&captures);
+        assert!((path1.fund_power + 30.060).abs() < 1e-3);
+        assert!((path1.noise_per_hz + 150.060).abs() < 1e-3);
+        assert_eq!(path1.snr, 40.0);
+
+        let paths: Vec<_> = [&captures[..]].iter()
+            .map(|captures| RfMetrics::stats(&captures.iter().map(|res| res.0.clone()).collect::<Vec<_>>()))
+            .collect();
+        assert!(!spread_exceeds(&paths, 1.0));
+        assert!(spread_exceeds(&paths, 0.5));
+        assert!(check_spread_threshold(0.0).is_ok());
+        assert!(check_spread_threshold(-1.0).is_err() && check_spread_threshold(f64::NAN).is_err());
+    }
 
     // #[test]
     // fn test_calc_metric() {
@@ -471,7 +893,7 @@ mod tests {
 
         file.add_file("test/hb_iq_0_0_00.txt".into());
         file.sort_file()
-            .parse_and_write()
+            .parse_and_write(Path::new("iq_dump"))
             .unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use crate::mock::{write_iq_text, CaptureSource};
+use crate::config::GainIndex;
+
+/// Receive chain model behind the mock server.
+///
+/// The gain of each path follows the last `SetReg` to the band's AGC register
+/// in `gain_regs`: the low half word drives path1 and the high half word path2.
+/// Powers are in dBFS relative to the 12-bit ADC full scale.
+pub struct RfFrontEnd {
+    /// AGC override register of each band, 0x30c02f88 for HB and 0x20c02f88 for LB.
+    pub gain_regs: HashMap<String, u32>,
+    pub tone_mhz: f64,
+    pub fs_mhz: f64,
+    pub samples: usize,
+    /// Tone level at the ADC with every stage at index 0.
+    pub input_dbfs: f64,
+    pub fem_gain_db: Vec<f64>,
+    pub lna_gain_db: Vec<f64>,
+    pub vga_gain_db: Vec<f64>,
+    /// Total noise power at the ADC, independent of gain.
+    pub noise_floor_dbfs: f64,
+    pub dc_offset: (i16, i16),
+    pub iq_gain_imbalance_db: f64,
+    pub iq_phase_imbalance_deg: f64,
+    pub clip_level: i16,
+    rng: XorShift,
+}
+
+impl Default for RfFrontEnd {
+    fn default() -> Self {
+        Self {
+            gain_regs: HashMap::from([("HB".into(), 0x30c02f88), ("LB".into(), 0x20c02f88)]),
+            tone_mhz: 2.5,
+            fs_mhz: 40.0,
+            samples: 4096,
+            input_dbfs: -60.0,
+            fem_gain_db: vec![0.0, 12.0],
+            lna_gain_db: (0..8).map(|x| x as f64 * 3.0).collect(),
+            vga_gain_db: (0..32).map(|x| x as f64).collect(),
+            noise_floor_dbfs: -70.0,
+            dc_offset: (0, 0),
+            iq_gain_imbalance_db: 0.0,
+            iq_phase_imbalance_deg: 0.0,
+            clip_level: 2047,
+            rng: XorShift(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+}
+
+impl RfFrontEnd {
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = XorShift(seed.max(1));
+        self
+    }
+
+    /// Total gain in dB for a gain index, 0 dB for entries missing from the table.
+    pub fn gain_db(&self, gain: GainIndex) -> f64 {
+        let lookup = |table: &[f64], idx: u8| table.get(idx as usize).copied().unwrap_or(0.0);
+        lookup(&self.fem_gain_db, gain.fem) + lookup(&self.lna_gain_db, gain.lna) + lookup(&self.vga_gain_db, gain.vga)
+    }
+
+    fn path(&mut self, gain: GainIndex, phase: f64) -> Vec<(i16, i16)> {
+        let full_scale = 2047.0;
+        let amplitude = full_scale * 10f64.powf((self.input_dbfs + self.gain_db(gain)) / 20.0);
+        let sigma = full_scale * (10f64.powf(self.noise_floor_dbfs / 10.0) / 2.0).sqrt();
+        let g = 10f64.powf(self.iq_gain_imbalance_db / 20.0);
+        let phi = self.iq_phase_imbalance_deg.to_radians();
+        let step = 2.0 * PI * self.tone_mhz / self.fs_mhz;
+        let clip = self.clip_level as f64;
+
+        (0..self.samples)
+            .map(|n| {
+                let arg = step * n as f64 + phase;
+                let i = amplitude * arg.cos() + sigma * self.rng.gaussian();
+                let q = amplitude * arg.sin() + sigma * self.rng.gaussian();
+                let q = g * (q * phi.cos() - i * phi.sin());
+                let i = (i + self.dc_offset.0 as f64).round().clamp(-clip - 1.0, clip);
+                let q = (q + self.dc_offset.1 as f64).round().clamp(-clip - 1.0, clip);
+                (i as i16, q as i16)
+            })
+            .collect()
+    }
+}
+
+impl CaptureSource for RfFrontEnd {
+    fn capture(&mut self, band: &str, regs: &HashMap<u32, u32>) -> Vec<u8> {
+        let value = self.gain_regs.get(band)
+            .and_then(|reg| regs.get(reg))
+            .copied()
+            .unwrap_or(0);
+        let gain1 = GainIndex::from_word(value as u16);
+        let gain2 = GainIndex::from_word((value >> 16) as u16);
+        let path1 = self.path(gain1, 0.0);
+        let path2 = self.path(gain2, PI / 4.0);
+        write_iq_text(&path1, &path2)
+    }
+}
+
+/// Small deterministic generator so captures are reproducible without extra deps.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal sample (Box-Muller).
+    fn gaussian(&mut self) -> f64 {
+        let u1 = self.next_f64().max(f64::MIN_POSITIVE);
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::client::Dut;
+    use crate::config::{Band, GainIndex};
+    use crate::mock::{CaptureSource, MockBoard, MockServer};
+    use crate::rfmetrics::{CalcMetric, FileParser};
+    use crate::sim::RfFrontEnd;
+
+    fn capture_metrics(sim: &mut RfFrontEnd, fem: u8, lna: u8, vga: u8) -> (f64, f64) {
+        let word = GainIndex { fem, lna, vga }.to_word() as u32;
+        let regs = HashMap::from([(0x30c02f88, (word | 0x3000) << 16 | (word | 0x1000))]);
+        let text = sim.capture("HB", &regs);
+        let ((i, q), _) = FileParser::parse_iq(text.as_slice()).unwrap();
+        let metrics = (i, q, 40).calc_metric();
+        (metrics.fund_freq, metrics.fund_power)
+    }
+
+    #[test]
+    fn test_unpack_gain_word() {
+        let gain = GainIndex::from_word(GainIndex { fem: 1, lna: 5, vga: 17 }.to_word() | 0x1000);
+        assert_eq!(gain, GainIndex { fem: 1, lna: 5, vga: 17 });
+    }
+
+    #[test]
+    fn test_metric_tracks_gain_table() {
+        let mut sim = RfFrontEnd::default().with_seed(7);
+        let (freq, low) = capture_metrics(&mut sim, 0, 0, 0);
+        let (_, high) = capture_metrics(&mut sim, 1, 2, 10);
+
+        assert!((freq - sim.tone_mhz).abs() < 0.05, "fund_freq {}", freq);
+        assert!((low - sim.input_dbfs).abs() < 0.5, "fund_power {}", low);
+        assert!((high - low - 28.0).abs() < 0.5, "gain step {}", high - low);
+    }
+
+    #[test]
+    fn test_clipping_limits_power() {
+        let mut sim = RfFrontEnd::default().with_seed(7);
+        let (_, power) = capture_metrics(&mut sim, 1, 7, 31);
+        let linear = sim.input_dbfs + sim.gain_db(GainIndex { fem: 1, lna: 7, vga: 31 });
+        assert!(power < linear - 2.0, "fund_power {} vs linear {}", power, linear);
+    }
+
+    #[test]
+    fn test_capture_in_memory() {
+        let board = MockBoard::new(Box::new(RfFrontEnd::default().with_seed(3)));
+        let server = MockServer::with_board("127.0.0.1:0", board).unwrap();
+        let addr = server.spawn().unwrap();
+
+        let mut dut = Dut::connect(&addr.to_string()).unwrap();
+        dut.fix_gain(Band::LB, 0, 4, 6).unwrap();
+        let capture = dut.capture(Band::LB, "LB_iq_0_4_06.txt", false).unwrap();
+        let (path1, path2) = capture.calc_metrics(40);
+
+        assert_eq!(capture.path1.0.len(), 4096);
+        assert!(dut.file_list.file_list.is_empty());
+        assert!((path1.fund_power + 42.0).abs() < 0.5, "fund_power {}", path1.fund_power);
+        assert!((path2.fund_power + 42.0).abs() < 0.5, "fund_power {}", path2.fund_power);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use anyhow::{anyhow, Context};
+use pyo3::pyclass;
+
+/// Register values read from the board, keyed by address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegSnapshot {
+    pub regs: BTreeMap<u32, u32>,
+}
+
+/// One register that differs between two snapshots; `None` when the register
+/// is only in the other snapshot.
+#[pyclass(get_all)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegChange {
+    pub addr: u32,
+    pub before: Option<u32>,
+    pub after: Option<u32>,
+}
+
+impl RegSnapshot {
+    /// Word addresses covered by `ranges`, both ends inclusive.
+    pub fn addresses(ranges: &[RangeInclusive<u32>]) -> impl Iterator<Item = u32> + '_ {
+        ranges.iter().flat_map(|range| range.clone().step_by(4))
+    }
+
+    /// Stored as `{"0x30c02f88": "0x34041404", ...}` so the file is readable next to `devmem` logs.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let regs: BTreeMap<String, String> = self.regs.iter()
+            .map(|(addr, value)| (format!("0x{:08x}", addr), format!("0x{:08x}", value)))
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&regs)?)
+            .with_context(|| format!("Could not write register snapshot {}", path.display()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read register snapshot {}", path.display()))?;
+        let regs: BTreeMap<String, String> = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid register snapshot {}", path.display()))?;
+        let regs = regs.iter()
+            .map(|(addr, value)| Ok((parse_hex(addr)?, parse_hex(value)?)))
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("Invalid register snapshot {}", path.display()))?;
+        Ok(Self { regs })
+    }
+
+    /// Registers whose value changed from `self` to `after`.
+    pub fn diff(&self, after: &RegSnapshot) -> Vec<RegChange> {
+        let mut addrs: Vec<u32> = self.regs.keys().chain(after.regs.keys()).copied().collect();
+        addrs.sort_unstable();
+        addrs.dedup();
+        addrs.into_iter()
+            .map(|addr| RegChange { addr, before: self.regs.get(&addr).copied(), after: after.regs.get(&addr).copied() })
+            .filter(|change| change.before != change.after)
+            .collect()
+    }
+}
+
+fn parse_hex(text: &str) -> anyhow::Result<u32> {
+    let hex = text.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(hex, 16).map_err(|_| anyhow!("Invalid register value {:?}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use crate::mock::TempDirGuard;
+    use crate::snapshot::{RegChange, RegSnapshot};
+
+    #[test]
+    fn test_save_load_diff() {
+        let before = RegSnapshot { regs: BTreeMap::from([(0x04e00030, 0), (0x30c02f88, 0x2d170d17)]) };
+        let dir = TempDirGuard::new("snapshot");
+        let path = dir.join("snapshot.json");
+        before.save(&path).unwrap();
+        assert_eq!(RegSnapshot::load(&path).unwrap(), before);
+
+        let after = RegSnapshot { regs: BTreeMap::from([(0x04e00030, 0xffff), (0x30c02f88, 0x2d170d17), (0x04e00478, 7)]) };
+        assert_eq!(before.diff(&after), vec![
+            RegChange { addr: 0x04e00030, before: Some(0), after: Some(0xffff) },
+            RegChange { addr: 0x04e00478, before: None, after: Some(7) },
+        ]);
+        assert_eq!(RegSnapshot::addresses(&[0x10..=0x18, 0x40..=0x40]).collect::<Vec<_>>(), vec![0x10, 0x14, 0x18, 0x40]);
+    }
+}
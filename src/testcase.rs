@@ -1,103 +1,496 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use anyhow::{anyhow, Context};
+use pyo3::{pyclass, pymethods};
+use strum::Display;
 use crate::client::Dut;
-use crate::config::{Band, GainType, TestBand};
+use crate::config::{Band, BandState, GainIndex, RxConfig, TestBand};
+use crate::rfmetrics::{IqCapture, RfMetrics};
+
+/// How one capture of a sweep ended.
+#[derive(PartialEq, Eq, Debug, Display, Clone, Copy)]
+#[strum(serialize_all = "lowercase")]
+pub enum PointStatus {
+    Ok,
+    Failed,
+    /// Not attempted: the sweep could not start or was interrupted.
+    Skipped,
+}
+
+/// One capture of a sweep: which point it was, how it ended and what it produced.
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct PointResult {
+    #[pyo3(get)]
+    pub(crate) band: String,
+    /// Channel of a channel sweep.
+    #[pyo3(get)]
+    pub(crate) freq: Option<u32>,
+    #[pyo3(get)]
+    pub(crate) gain: GainIndex,
+    #[pyo3(get)]
+    pub(crate) repeat: u32,
+    pub(crate) status: PointStatus,
+    #[pyo3(get)]
+    pub(crate) error: Option<String>,
+    /// Seconds spent on the capture, retries included.
+    #[pyo3(get)]
+    pub(crate) elapsed: f64,
+    /// Local copy of the dump.
+    #[pyo3(get)]
+    pub(crate) file: Option<String>,
+    /// Both paths' metrics, when the dump could be parsed.
+    #[pyo3(get)]
+    pub(crate) metrics: Option<(RfMetrics, RfMetrics)>,
+}
+
+impl PointResult {
+    fn new(band: Band, freq: Option<u32>, gain: GainIndex, repeat: u32) -> Self {
+        Self {
+            band: band.to_string(),
+            freq,
+            gain,
+            repeat,
+            status: PointStatus::Skipped,
+            error: None,
+            elapsed: 0.0,
+            file: None,
+            metrics: None,
+        }
+    }
+
+    pub fn status(&self) -> PointStatus {
+        self.status
+    }
+}
+
+#[pymethods]
+impl PointResult {
+    /// `"ok"`, `"failed"` or `"skipped"`.
+    #[getter(status)]
+    fn py_status(&self) -> String {
+        self.status.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("PointResult({})", self)
+    }
+}
+
+/// `HB 5180 MHz 0_3_10 r0 failed: ...`
+impl fmt::Display for PointResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.band)?;
+        if let Some(freq) = self.freq {
+            write!(f, " {} MHz", freq)?;
+        }
+        write!(f, " {}_{}_{} r{} {}", self.gain.fem, self.gain.lna, self.gain.vga, self.repeat, self.status)?;
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every capture of a sweep, in the order they ran.
+#[derive(Debug, Clone, Default)]
+pub struct SweepReport {
+    pub(crate) points: Vec<PointResult>,
+}
+
+impl SweepReport {
+    pub fn points(&self) -> &[PointResult] {
+        &self.points
+    }
+
+    /// Captures that did not end `Ok`.
+    pub fn failures(&self) -> impl Iterator<Item = &PointResult> {
+        self.points.iter().filter(|point| point.status != PointStatus::Ok)
+    }
+
+    pub fn extend(&mut self, other: SweepReport) {
+        self.points.extend(other.points);
+    }
+}
 
 pub trait TestCase {
-    fn traverse(&self) -> impl Iterator<Item=u8>;
+    /// Gain points of the sweep, in order.
+    fn points(&self) -> Vec<GainIndex>;
     fn get_band(&self) -> Band;
 
-    fn get_gain_type(&self) -> GainType;
+    /// Captures per gain point.
+    fn repeats(&self) -> u32 {
+        1
+    }
 
-    fn run_single_fem(&self, idx:u8, dut: &mut Dut) -> anyhow::Result<()> {
-        let band = self.get_band();
-        dut.fix_gain(band, idx, 0, 0)?;
-        let iq_name = format!("{}_iq_{}_0_00.txt", band, idx);
-        dut.dump_iq(band, iq_name.clone())?;
-        dut.copy_files(iq_name)?;
-        dut.del_files()?;
-        Ok(())
+    /// Checked before anything is sent to the board.
+    fn validate(&self) -> anyhow::Result<()> {
+        let points = self.points();
+        if points.is_empty() {
+            return Err(anyhow!("Nothing to sweep on {}", self.get_band()));
+        }
+        points.iter().try_for_each(|point| point.validate())
     }
-    fn run_single_lna(&self, idx:u8, dut: &mut Dut) -> anyhow::Result<()> {
-        let band = self.get_band();
-        dut.fix_gain(band, 0, idx, 0)?;
-        let iq_name = format!("{}_iq_0_{}_00.txt", band, idx);
-        dut.dump_iq(band, iq_name.clone())?;
-        dut.copy_files(iq_name)?;
-        dut.del_files()?;
-        Ok(())
+
+    /// Channel the sweep runs on, if it is one of several.
+    fn channel(&self) -> Option<u32> {
+        None
+    }
+
+    /// Every (point, repeat) of the sweep, in capture order.
+    fn captures(&self) -> Vec<(GainIndex, u32)> {
+        let repeats = self.repeats();
+        self.points().into_iter()
+            .flat_map(|gain| (0..repeats).map(move |repeat| (gain, repeat)))
+            .collect()
+    }
+
+    /// Every capture of the sweep, skipped because of `error`.
+    fn skipped(&self, error: &str) -> SweepReport {
+        let points = self.captures().into_iter()
+            .map(|(gain, repeat)| PointResult {
+                error: Some(error.to_string()),
+                ..PointResult::new(self.get_band(), self.channel(), gain, repeat)
+            })
+            .collect();
+        SweepReport { points }
     }
-    fn run_single_vga(&self, idx:u8, dut: &mut Dut) -> anyhow::Result<()> {
+
+    /// Start of the dump file names, before `_iq_`.
+    fn file_prefix(&self) -> String {
+        self.get_band().to_string()
+    }
+
+    /// `gain.file_name`, with `_r{repeat}` before `.txt` when a point is captured more than once.
+    fn dump_name(&self, gain: GainIndex, repeat: u32) -> String {
+        let name = gain.file_name(&self.file_prefix());
+        if self.repeats() > 1 {
+            name.replace(".txt", &format!("_r{}.txt", repeat))
+        } else {
+            name
+        }
+    }
+
+    /// Capture one point and return the local dump file.
+    fn run_single(&self, gain: GainIndex, repeat: u32, dut: &mut Dut) -> anyhow::Result<PathBuf> {
         let band = self.get_band();
-        dut.fix_gain(band, 0, 0, idx)?;
-        let iq_name = format!("{}_iq_0_0_{:02}.txt", band, idx);
+        dut.fix_gain(band, gain.fem, gain.lna, gain.vga)?;
+        let iq_name = self.dump_name(gain, repeat);
         dut.dump_iq(band, iq_name.clone())?;
-        dut.copy_files(iq_name)?;
+        dut.copy_files(iq_name.clone())?;
         dut.del_files()?;
-        Ok(())
+        Ok(dut.dump_path(&iq_name))
     }
 
-    fn run_test(&self, dut: &mut Dut) {
-        match self.get_gain_type() {
-            GainType::Fem(_) => {
-                self.traverse()
-                    .for_each(|x| {
-                        match self.run_single_fem(x, dut) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("Run test Error: {}", e)
-                            }
-                        }
-                    })
+    /// Run the sweep; a failing point is logged and the sweep goes on, the
+    /// report tells which points failed and why.
+    fn run_test(&self, dut: &mut Dut) -> SweepReport {
+        if let Err(e) = self.validate() {
+            log::error!("Run test Error: {:#}", e);
+            return self.skipped(&format!("{:#}", e));
+        }
+        if let Err(e) = dut.require_band(self.get_band(), BandState::RxOpen, "run_test") {
+            log::error!("Run test Error: {}", e);
+            return self.skipped(&format!("{:#}", e));
+        }
+        let snapshot = if dut.options().restore_registers {
+            let ranges = dut.snapshot_ranges();
+            match dut.resilient(|dut| dut.snapshot_regs(&ranges)) {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    log::error!("Register snapshot Error: {}", e);
+                    None
+                }
             }
-            GainType::Lna(_) => {
-                self.traverse()
-                    .for_each(|x| {
-                        match self.run_single_lna(x, dut) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("Run test Error: {}", e)
-                            }
-                        }
-                    })
+        } else {
+            None
+        };
+
+        let report = self.sweep(dut);
+
+        if let Some(snapshot) = snapshot {
+            match dut.resilient(|dut| dut.restore_regs(&snapshot)) {
+                Ok(n) => log::info!("Restored {} registers after sweep", n),
+                Err(e) => log::error!("Register restore Error: {}", e),
             }
-            GainType::Vga(_) => {
-                self.traverse()
-                    .for_each(|x| {
-                        match self.run_single_vga(x, dut) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::error!("Run test Error: {}", e)
-                            }
+        }
+        report
+    }
+
+    fn sweep(&self, dut: &mut Dut) -> SweepReport {
+        let mut report = SweepReport::default();
+        let mut stopped = None;
+        for (gain, repeat) in self.captures() {
+            let mut point = PointResult::new(self.get_band(), self.channel(), gain, repeat);
+            if stopped.is_none() && let Err(e) = dut.check_interrupt() {
+                log::error!("Run test stopped: {}", e);
+                stopped = Some(format!("{:#}", e));
+            }
+            if stopped.is_some() {
+                point.error = stopped.clone();
+                report.points.push(point);
+                continue;
+            }
+            let start = Instant::now();
+            let res = dut.resilient(|dut| self.run_single(gain, repeat, dut));
+            point.elapsed = start.elapsed().as_secs_f64();
+            match res {
+                Ok(file) => {
+                    let name = file.display().to_string();
+                    match file_metrics(&file) {
+                        Ok(metrics) => {
+                            point.status = PointStatus::Ok;
+                            dut.file_list.add_metrics(name.clone(), metrics.clone());
+                            point.metrics = Some(metrics);
                         }
-                    })
+                        Err(e) => {
+                            log::error!("Run test Error: {:#}", e);
+                            point.status = PointStatus::Failed;
+                            point.error = Some(format!("{:#}", e));
+                        }
+                    }
+                    point.file = Some(name);
+                }
+                Err(e) => {
+                    log::error!("Run test Error: {}", e);
+                    point.status = PointStatus::Failed;
+                    point.error = Some(format!("{:#}", e));
+                }
             }
+            report.points.push(point);
         }
+        report
     }
 
 }
 
 impl TestCase for TestBand {
-    fn traverse(&self) -> impl Iterator<Item=u8> {
-        match self {
-            TestBand::HB(item) => {
-                item.return_iter()
-            }
-            TestBand::LB(item) => {
-                item.return_iter()
-            }
-        }
+    fn points(&self) -> Vec<GainIndex> {
+        self.gain.points()
     }
+
+    fn repeats(&self) -> u32 {
+        self.repeats
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        TestBand::validate(self)
+    }
+
     fn get_band(&self) -> Band {
-        match self {
-            TestBand::HB(_) => {
-                Band::HB
+        self.band
+    }
+}
+/// The same gain sweep repeated on several channels of one band. Dumps are
+/// named `{band}_ch{freq}_iq_...` so the workbook gets a sheet per channel.
+pub struct ChannelSweep {
+    pub gain: TestBand,
+    pub channels: Vec<RxConfig>,
+}
+
+/// A sweep on one channel of several, named `{band}_ch{freq}_iq_...`.
+pub(crate) struct OnChannel<'a> {
+    pub(crate) gain: &'a TestBand,
+    pub(crate) freq: u32,
+}
+
+impl TestCase for OnChannel<'_> {
+    fn points(&self) -> Vec<GainIndex> {
+        self.gain.points()
+    }
+
+    fn repeats(&self) -> u32 {
+        self.gain.repeats
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.gain.validate()
+    }
+
+    fn get_band(&self) -> Band {
+        self.gain.get_band()
+    }
+
+    fn channel(&self) -> Option<u32> {
+        Some(self.freq)
+    }
+
+    fn file_prefix(&self) -> String {
+        format!("{}_ch{}", self.get_band(), self.freq)
+    }
+}
+
+impl ChannelSweep {
+    pub fn run_test(&self, dut: &mut Dut) -> SweepReport {
+        let band = self.gain.get_band();
+        let mut report = SweepReport::default();
+        let mut stopped = None;
+        for config in &self.channels {
+            let on_channel = OnChannel { gain: &self.gain, freq: config.freq };
+            if stopped.is_none() && let Err(e) = dut.check_interrupt() {
+                log::error!("Run test stopped: {}", e);
+                stopped = Some(format!("{:#}", e));
             }
-            TestBand::LB(_) => {
-                Band::LB
+            if let Some(e) = &stopped {
+                report.extend(on_channel.skipped(e));
+                continue;
             }
+            if dut.band_state(band) >= BandState::RxOpen
+                && let Err(e) = dut.resilient(|dut| dut.close_rx(band)) {
+                log::error!("Run test Error: {}", e);
+            }
+            if let Err(e) = dut.resilient(|dut| dut.open_rx_with(band, config)) {
+                log::error!("Skip channel {} MHz: {}", config.freq, e);
+                report.extend(on_channel.skipped(&format!("{:#}", e)));
+                continue;
+            }
+            log::info!("Sweep {} on {} MHz", band, config.freq);
+            report.extend(on_channel.run_test(dut));
         }
+        report
+    }
+}
+
+/// Metrics of both paths of a dump file.
+fn file_metrics(file: &Path) -> anyhow::Result<(RfMetrics, RfMetrics)> {
+    let reader = std::io::BufReader::new(std::fs::File::open(file)?);
+    let capture = IqCapture::parse(reader).with_context(|| format!("parse {}", file.display()))?;
+    Ok(capture.calc_metrics(40))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::client::DutOptions;
+    use crate::config::{Band, GainIndex, GainSweep, GainType, RxConfig, TestBand};
+    use crate::mock::{mock_dut, mock_dut_with, GarbledSource, MockBoard};
+    use crate::rfmetrics::FileParser;
+    use crate::testcase::{ChannelSweep, PointStatus, TestCase};
+
+    #[test]
+    fn test_sweep_report() {
+        let (board, mut dut, dump_dir) = mock_dut("report");
+        let report = TestBand::new(Band::HB, GainType::Vga((0..3).collect())).run_test(&mut dut);
+        assert_eq!(report.points().len(), 3);
+        assert_eq!(report.failures().count(), 0);
+        let last = &report.points()[2];
+        let file = dump_dir.join("HB_iq_0_0_02.txt").display().to_string();
+        assert_eq!((last.gain, last.repeat, last.freq), (GainIndex::new(0, 0, 2), 0, None));
+        assert_eq!(last.file.as_ref(), Some(&file));
+        // the workbook reuses the metrics instead of parsing the dump again
+        let cached = &dut.file_list.metrics[&file];
+        assert_eq!(last.metrics.as_ref().map(|m| m.0.fund_power), Some(cached.0.fund_power));
+
+        // a write that does not stick fails the point, the sweep goes on
+        board.lock().unwrap().read_only.insert(0x30c02f88);
+        dut.set_options(DutOptions { verify_writes: true, ..DutOptions::default() }).unwrap();
+        let report = TestBand::new(Band::HB, GainType::Vga(vec![4, 5])).run_test(&mut dut);
+        assert_eq!(report.failures().count(), 2);
+        assert_eq!(report.points()[0].status(), PointStatus::Failed);
+        assert!(report.points()[0].file.is_none() && report.points()[0].error.is_some());
+    }
+
+    #[test]
+    fn test_sweep_garbled_dump() {
+        let (_board, mut dut, _dump_dir) = mock_dut_with("garbled_sweep", MockBoard::new(Box::new(GarbledSource)));
+        let report = TestBand::new(Band::HB, GainType::Vga(vec![0, 1])).run_test(&mut dut);
+        assert_eq!(report.failures().count(), 2);
+        let point = &report.points()[0];
+        assert!(point.file.is_some() && point.metrics.is_none());
+        assert!(point.error.as_ref().unwrap().contains("line 2"), "{:?}", point.error);
+        assert!(dut.file_list.metrics.is_empty());
     }
 
-    fn get_gain_type(&self) -> GainType {
-        self.return_gain_type()
+    #[test]
+    fn test_reject_out_of_range_gain() {
+        let (board, mut dut, _dump_dir) = mock_dut("range");
+        // 32 does not fit the 5-bit vga field, so nothing of this sweep runs
+        let report = TestBand::new(Band::HB, GainType::Vga(vec![8, 32])).run_test(&mut dut);
+        assert!(report.points().iter().all(|point| point.status() == PointStatus::Skipped));
+        assert!(report.points()[1].error.as_ref().unwrap().contains("out of range"));
+        assert!(dut.fix_gain(Band::HB, 0, 8, 0).is_err());
+        assert!(dut.file_list.file_list.is_empty());
+        assert!(!board.lock().unwrap().regs.contains_key(&0x30c02f88));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_cartesian_sweep() {
+        let (board, mut dut, _dump_dir) = mock_dut("cartesian");
+        let sweep = GainSweep::cartesian(vec![0, 1], Vec::new(), vec![4, 12]).with_base(GainIndex::new(0, 3, 0));
+        TestBand::new(Band::HB, sweep).run_test(&mut dut);
+
+        let files: Vec<_> = dut.file_list.file_list.iter()
+            .map(|f| std::path::Path::new(f).file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(files, vec!["HB_iq_0_3_04.txt", "HB_iq_0_3_12.txt", "HB_iq_1_3_04.txt", "HB_iq_1_3_12.txt"]);
+        let gain = GainIndex::new(1, 3, 12).to_word() as u32;
+        assert_eq!(board.lock().unwrap().regs[&0x30c02f88], (gain | 0x3000) << 16 | (gain | 0x1000));
+    }
+
+    #[test]
+    fn test_restore_registers_after_sweep() {
+        let mut board = MockBoard::default();
+        board.regs.insert(0x30c02f88, 0x1234);
+        let (board, mut dut, _dump_dir) = mock_dut_with("restore", board);
+        dut.set_options(DutOptions { restore_registers: true, ..DutOptions::default() }).unwrap();
+        let before = dut.snapshot_regs(&dut.snapshot_ranges()).unwrap();
+        TestBand::new(Band::HB, GainType::Vga((0..2).collect())).run_test(&mut dut);
+
+        assert_eq!(dut.file_list.file_list.len(), 2);
+        assert_eq!(board.lock().unwrap().regs[&0x30c02f88], 0x1234);
+        dut.reconnect().unwrap();
+        assert_eq!(board.lock().unwrap().regs[&0x30c02f88], 0x1234);
+        dut.shut_down_band(Band::HB).unwrap();
+        let after = dut.snapshot_regs(&dut.snapshot_ranges()).unwrap();
+        assert_eq!(before.diff(&after).len(), 3);
+        assert_eq!(dut.restore_regs(&before).unwrap(), 3);
+        assert_eq!(board.lock().unwrap().regs[&0x04e00030], 0);
+    }
+
+    #[test]
+    fn test_channel_sweep() {
+        let (board, mut dut, dump_dir) = mock_dut("channels");
+        dut.open_rx(Band::HB).unwrap();
+        let report = dut.run_channel_sweep(ChannelSweep {
+            gain: TestBand::new(Band::HB, GainType::Vga((0..2).collect())),
+            channels: vec![RxConfig::new(5180), RxConfig::new(5500)],
+        });
+        let freqs: Vec<_> = report.points().iter().map(|point| point.freq).collect();
+        assert_eq!(freqs, vec![Some(5180), Some(5180), Some(5500), Some(5500)]);
+
+        assert_eq!(dut.file_list.file_list.len(), 4);
+        assert!(dump_dir.join("HB_ch5500_iq_0_0_01.txt").exists());
+        let fastconfig = board.lock().unwrap().ate_log.iter()
+            .map(|line| line.split(" -w").next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(fastconfig, vec![
+            "ate_cmd wlan0 fastconfig -f 5180 -c 5180", "ate_cmd wlan0 fastconfig -k",
+            "ate_cmd wlan0 fastconfig -f 5180 -c 5180", "ate_cmd wlan0 fastconfig -k",
+            "ate_cmd wlan0 fastconfig -f 5500 -c 5500",
+        ]);
+
+        let mut parser = FileParser::new(dut.file_list.file_list.clone()).sort_file();
+        parser.write_workbook(&dump_dir.join("result.xlsx")).unwrap();
+        assert!(dump_dir.join("result.xlsx").exists());
+    }
+
+    #[test]
+    fn test_reconnect_resumes_sweep() {
+        let (board, mut dut, _dump_dir) = mock_dut("reconnect");
+        board.lock().unwrap().drop_after = Some(9);
+        board.lock().unwrap().stall_after = Some(20);
+        dut.set_options(DutOptions {
+            read_timeout: Some(Duration::from_millis(200)),
+            retry_delay: Duration::from_millis(10),
+            ..DutOptions::default()
+        }).unwrap();
+        dut.ate_init().unwrap();
+        TestBand::new(Band::LB, GainType::Lna((0..4).collect())).run_test(&mut dut);
+
+        assert_eq!(dut.file_list.file_list.len(), 4);
+        let board = board.lock().unwrap();
+        assert!(board.drop_after.is_none() && board.stall_after.is_none());
+        assert_eq!(board.regs.get(&0x20c02f88), Some(&0x34c014c0));
+    }
+}
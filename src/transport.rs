@@ -0,0 +1,281 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+use anyhow::Context;
+
+/// Byte stream a `Dut` talks the DumpCommand protocol over.
+pub trait Transport: Read + Write + Send + Sync {
+    /// Bound blocking reads/writes; `None` blocks forever. Transports without
+    /// timeout support ignore this.
+    fn set_timeouts(&mut self, _read: Option<Duration>, _write: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_timeouts(&mut self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(read)?;
+        self.set_write_timeout(write)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn set_timeouts(&mut self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(read)?;
+        self.set_write_timeout(write)
+    }
+}
+
+/// Serial console or pty. Line settings (baud rate, raw mode) are expected to be
+/// configured beforehand, e.g. with `stty`.
+pub struct SerialTransport {
+    port: File,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl SerialTransport {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let port = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self { port, read_timeout: None, write_timeout: None })
+    }
+
+    /// Wait until the port can be read (or written), or fail with `TimedOut`.
+    #[cfg(unix)]
+    fn wait(&self, write: bool, timeout: Option<Duration>) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let Some(timeout) = timeout else {
+            return Ok(());
+        };
+        let (events, what) = if write { (libc::POLLOUT, "write") } else { (libc::POLLIN, "read") };
+        let mut fd = libc::pollfd { fd: self.port.as_raw_fd(), events, revents: 0 };
+        let millis = timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+        loop {
+            // SAFETY: `fd` is a single valid pollfd that outlives the call.
+            match unsafe { libc::poll(&mut fd, 1, millis) } {
+                0 => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("serial {} timed out", what))),
+                n if n > 0 => return Ok(()),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// `set_timeouts` refuses timeouts here, so there is nothing to wait for.
+    #[cfg(not(unix))]
+    fn wait(&self, _write: bool, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.wait(false, self.read_timeout)?;
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.wait(true, self.write_timeout)?;
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn set_timeouts(&mut self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        if cfg!(not(unix)) && (read.is_some() || write.is_some()) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                      "serial timeouts need unix, set read_timeout and write_timeout to None"));
+        }
+        self.read_timeout = read;
+        self.write_timeout = write;
+        Ok(())
+    }
+}
+
+/// One end of an in-process duplex pipe; reads return EOF once the peer is dropped.
+pub struct MemoryTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Mutex<Receiver<Vec<u8>>>,
+    pending: Vec<u8>,
+    pos: usize,
+    read_timeout: Option<Duration>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (Self, Self) {
+        let (tx_a, rx_b) = channel();
+        let (tx_b, rx_a) = channel();
+        (
+            Self { tx: tx_a, rx: Mutex::new(rx_a), pending: Vec::new(), pos: 0, read_timeout: None },
+            Self { tx: tx_b, rx: Mutex::new(rx_b), pending: Vec::new(), pos: 0, read_timeout: None },
+        )
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.pending.len() {
+            let rx = self.rx.get_mut().unwrap();
+            let chunk = match self.read_timeout {
+                Some(timeout) => rx.recv_timeout(timeout),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match chunk {
+                Ok(chunk) => {
+                    self.pending = chunk;
+                    self.pos = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "memory transport read timed out"));
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "memory transport peer closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn set_timeouts(&mut self, read: Option<Duration>, _write: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = read;
+        Ok(())
+    }
+}
+
+/// Whether an error means the link to the board is gone or hung, as opposed to
+/// the board rejecting a command.
+pub fn is_link_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::NotConnected
+        ))
+}
+
+/// Open a transport from an address string:
+/// `host:port` or `tcp:host:port`, `unix:/path/to/socket`, `serial:/dev/ttyUSB0`.
+pub fn open(addr: &str) -> anyhow::Result<Box<dyn Transport>> {
+    if let Some(path) = addr.strip_prefix("serial:") {
+        let port = SerialTransport::open(path)
+            .with_context(|| format!("Could not open serial port {}", path))?;
+        return Ok(Box::new(port));
+    }
+    if let Some(path) = addr.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let stream = std::os::unix::net::UnixStream::connect(path)
+                .with_context(|| format!("Could not connect to unix socket {}", path))?;
+            return Ok(Box::new(stream));
+        }
+        #[cfg(not(unix))]
+        anyhow::bail!("Unix domain sockets are not supported on this platform: {}", path);
+    }
+    let addr = addr.strip_prefix("tcp:").unwrap_or(addr);
+    let stream = TcpStream::connect(addr)
+        .with_context(|| format!("Could not connect to server {}", addr))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    use crate::client::{Dut, DutOptions};
+    use crate::mock::{serve, MockBoard};
+    use crate::transport::{is_link_error, open, MemoryTransport};
+
+    #[test]
+    fn test_dut_over_memory_transport() {
+        let (client, server) = MemoryTransport::pair();
+        let handle = thread::spawn(move || {
+            let board = Mutex::new(MockBoard::default());
+            serve(&board, server).unwrap();
+            board.into_inner().unwrap()
+        });
+
+        let mut dut = Dut::with_transport(Box::new(client));
+        dut.ate_init().unwrap();
+        assert!(dut.del_files().unwrap());
+        drop(dut);
+
+        assert!(handle.join().unwrap().ate_initialized);
+    }
+
+    #[test]
+    fn test_read_timeout_is_link_error() {
+        let (client, _server) = MemoryTransport::pair();
+        let mut dut = Dut::with_transport(Box::new(client));
+        dut.set_options(DutOptions { read_timeout: Some(Duration::from_millis(20)), ..DutOptions::default() })
+            .unwrap();
+        let err = dut.del_files().unwrap_err();
+        assert!(is_link_error(&err), "{:#}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serial_read_timeout() {
+        use std::io::{Read, Write};
+        use crate::mock::TempDirGuard;
+        use crate::transport::{SerialTransport, Transport};
+
+        // a fifo opened read-write loops back what is written, like a board that echoes
+        let dir = TempDirGuard::new("serial");
+        let path = dir.join("tty");
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let mut port = SerialTransport::open(path.to_str().unwrap()).unwrap();
+        port.set_timeouts(Some(Duration::from_millis(50)), Some(Duration::from_millis(50))).unwrap();
+
+        let mut buf = [0u8; 4];
+        let err = port.read(&mut buf).unwrap_err();
+        assert!(is_link_error(&err.into()));
+        port.write_all(b"ok\n").unwrap();
+        assert_eq!(port.read(&mut buf).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_open_reports_error() {
+        assert!(open("serial:/nonexistent/tty").is_err());
+        assert!(Dut::connect("127.0.0.1:1").is_err());
+    }
+}